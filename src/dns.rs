@@ -0,0 +1,377 @@
+use crate::udp;
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const SIZE_HEADER: usize = 12;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+// For the whole exchange over TCP, UDP queries are retried on a shorter timeout instead
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RECORD_TYPES: &[(&str, u16)] = &[
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", 28),
+    ("SRV", 33),
+];
+
+const RESPONSE_CODES: &[(&str, u8)] = &[
+    ("NOERROR", 0),
+    ("FORMERR", 1),
+    ("SERVFAIL", 2),
+    ("NXDOMAIN", 3),
+    ("NOTIMP", 4),
+    ("REFUSED", 5),
+];
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DnsResponse {
+    pub rcode: u8,
+    // Addresses from the A and AAAA records in the answer section
+    pub addresses: Vec<IpAddr>,
+}
+
+pub fn record_type_from_str(record_type: &str) -> Option<u16> {
+    RECORD_TYPES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(record_type))
+        .map(|(_, value)| *value)
+}
+
+pub fn rcode_from_str(rcode: &str) -> Option<u8> {
+    RESPONSE_CODES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(rcode))
+        .map(|(_, value)| *value)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// DNS Message Layout (RFC 1035):
+// ------------------------------------------------------------------------------------
+// | ID      | Flags   | QDCOUNT | ANCOUNT | NSCOUNT | ARCOUNT | Question | Answer ... |
+// ------------------------------------------------------------------------------------
+// | 2 bytes | 2 bytes | 2 bytes | 2 bytes | 2 bytes | 2 bytes | variable | variable   |
+// ------------------------------------------------------------------------------------
+// - Question: QNAME (length prefixed labels, ending with a 0 length label), QTYPE (2 bytes), QCLASS (2 bytes)
+// - Answer: NAME (labels, or a 2 byte pointer to labels earlier in the message), TYPE (2 bytes),
+//   CLASS (2 bytes), TTL (4 bytes), RDLENGTH (2 bytes), RDATA (RDLENGTH bytes)
+
+pub fn encode_name(name: &str) -> io::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid domain name: {}", name),
+            ));
+        }
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    Ok(encoded)
+}
+
+fn create_query(id: u16, name: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(SIZE_HEADER + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // 1 question, no answer, authority, or additional records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    query.extend_from_slice(&encode_name(name)?);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn read_u16(message: &[u8], offset: usize) -> io::Result<u16> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid_data("truncated DNS message"))
+}
+
+// Returns the offset right after the name. We never need the name itself, only to get past it.
+fn skip_name(message: &[u8], mut offset: usize) -> io::Result<usize> {
+    loop {
+        let length = *message
+            .get(offset)
+            .ok_or_else(|| invalid_data("truncated DNS name"))?;
+        match length {
+            0 => return Ok(offset + 1),
+            // Compression pointer, the rest of the name is somewhere else
+            length if length & 0xC0 == 0xC0 => return Ok(offset + 2),
+            length => offset += 1 + usize::from(length),
+        }
+    }
+}
+
+fn parse_response(message: &[u8], id: u16) -> io::Result<DnsResponse> {
+    if message.len() < SIZE_HEADER {
+        return Err(invalid_data("truncated DNS header"));
+    }
+    if read_u16(message, 0)? != id {
+        return Err(invalid_data("DNS response ID does not match the query"));
+    }
+
+    let flags = read_u16(message, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(invalid_data("DNS message is not a response"));
+    }
+
+    let question_count = read_u16(message, 4)?;
+    let answer_count = read_u16(message, 6)?;
+
+    let mut offset = SIZE_HEADER;
+    for _ in 0..question_count {
+        // QTYPE and QCLASS
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        offset = skip_name(message, offset)?;
+        let record_type = read_u16(message, offset)?;
+        let data_length = usize::from(read_u16(message, offset + 8)?);
+        let data_start = offset + 10;
+        let data = message
+            .get(data_start..data_start + data_length)
+            .ok_or_else(|| invalid_data("truncated DNS record"))?;
+
+        match (record_type, data_length) {
+            (TYPE_A, 4) => {
+                addresses.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                )));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+
+        offset = data_start + data_length;
+    }
+
+    Ok(DnsResponse {
+        rcode: (flags & 0x000F) as u8,
+        addresses,
+    })
+}
+
+async fn exchange_udp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = udp::connect_addr(server).await?;
+    udp::exchange(
+        &socket,
+        || Ok::<_, io::Error>(query.to_vec()),
+        // Replies to an earlier attempt carry the same ID, anything else is a stray packet
+        |response| (response.get(0..2) == query.get(0..2)).then(|| Ok(response.to_vec())),
+    )
+    .await?
+    .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "DNS server did not answer"))
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+
+        // Messages over TCP are prefixed with their length
+        let mut request = (query.len() as u16).to_be_bytes().to_vec();
+        request.extend_from_slice(query);
+        stream.write_all(&request).await?;
+
+        let length = stream.read_u16().await?;
+        let mut buffer = vec![0u8; usize::from(length)];
+        stream.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    };
+    tokio::time::timeout(TCP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS server did not answer"))?
+}
+
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    record_type: u16,
+    protocol: DnsProtocol,
+) -> io::Result<DnsResponse> {
+    // Only needs to be unpredictable enough to tell our response apart from stray packets
+    let id = std::time::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .subsec_nanos() as u16;
+    let query = create_query(id, name, record_type)?;
+
+    let response = match protocol {
+        DnsProtocol::Udp => exchange_udp(server, &query).await?,
+        DnsProtocol::Tcp => exchange_tcp(server, &query).await?,
+    };
+
+    parse_response(&response, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    // Answers a query the way a resolver would, with one A record per address, using a
+    // compression pointer back to the name in the question
+    fn create_response(query: &[u8], rcode: u8, addresses: &[Ipv4Addr]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        let flags = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080 | u16::from(rcode);
+        response[2..4].copy_from_slice(&flags.to_be_bytes());
+        response[6..8].copy_from_slice(&(addresses.len() as u16).to_be_bytes());
+        for address in addresses {
+            response.extend_from_slice(&[0xC0, SIZE_HEADER as u8]);
+            response.extend_from_slice(&TYPE_A.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&300u32.to_be_bytes());
+            response.extend_from_slice(&4u16.to_be_bytes());
+            response.extend_from_slice(&address.octets());
+        }
+        response
+    }
+
+    #[test]
+    fn test_encode_name() {
+        assert_eq!(
+            encode_name("nas.lan").unwrap(),
+            b"\x03nas\x03lan\x00".to_vec()
+        );
+        assert_eq!(
+            encode_name("nas.lan.").unwrap(),
+            b"\x03nas\x03lan\x00".to_vec()
+        );
+        assert!(encode_name("nas..lan").is_err());
+        assert!(encode_name(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = create_query(0x1234, "nas.lan", TYPE_A).unwrap();
+        let addresses = [
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 11),
+        ];
+        let response = create_response(&query, 0, &addresses);
+
+        assert_eq!(
+            parse_response(&response, 0x1234).unwrap(),
+            DnsResponse {
+                rcode: 0,
+                addresses: addresses.iter().map(|a| IpAddr::V4(*a)).collect()
+            }
+        );
+
+        // Wrong ID, or not a response at all
+        assert!(parse_response(&response, 0x4321).is_err());
+        assert!(parse_response(&query, 0x1234).is_err());
+        // Truncated records
+        assert!(parse_response(&response[..response.len() - 2], 0x1234).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_udp() {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            let (received, client) = socket.recv_from(&mut buffer).await.unwrap();
+            let response = create_response(&buffer[..received], 3, &[]);
+            socket.send_to(&response, client).await.unwrap();
+        });
+
+        let response = query(server, "missing.lan", TYPE_A, DnsProtocol::Udp)
+            .await
+            .unwrap();
+        assert_eq!(response.rcode, rcode_from_str("nxdomain").unwrap());
+        assert!(response.addresses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_tcp() {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let server = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let length = stream.read_u16().await.unwrap();
+            let mut query = vec![0u8; usize::from(length)];
+            stream.read_exact(&mut query).await.unwrap();
+
+            let response = create_response(&query, 0, &[Ipv4Addr::new(10, 0, 0, 53)]);
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        let response = query(server, "resolver.lan", TYPE_A, DnsProtocol::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(response.rcode, 0);
+        assert_eq!(
+            response.addresses,
+            vec!["10.0.0.53".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_timeout() {
+        // Neither server ever answers
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let error = query(
+            socket.local_addr().unwrap(),
+            "resolver.lan",
+            TYPE_A,
+            DnsProtocol::Udp,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let error = query(
+            listener.local_addr().unwrap(),
+            "resolver.lan",
+            TYPE_A,
+            DnsProtocol::Tcp,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
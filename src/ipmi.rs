@@ -0,0 +1,913 @@
+use crate::udp;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{Cipher, Crypter, Mode};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+// RMCP header for IPMI messages: version 6, reserved, no RMCP ACK, class IPMI
+const RMCP_HEADER: [u8; 4] = [0x06, 0x00, 0xFF, 0x07];
+const SIZE_SESSION_HEADER: usize = 12;
+const AUTH_TYPE_RMCP_PLUS: u8 = 0x06;
+const NEXT_HEADER: u8 = 0x07;
+
+const PAYLOAD_ENCRYPTED: u8 = 0x80;
+const PAYLOAD_AUTHENTICATED: u8 = 0x40;
+const PAYLOAD_IPMI: u8 = 0x00;
+const PAYLOAD_OPEN_SESSION_REQUEST: u8 = 0x10;
+const PAYLOAD_OPEN_SESSION_RESPONSE: u8 = 0x11;
+const PAYLOAD_RAKP1: u8 = 0x12;
+const PAYLOAD_RAKP2: u8 = 0x13;
+const PAYLOAD_RAKP3: u8 = 0x14;
+const PAYLOAD_RAKP4: u8 = 0x15;
+
+const BMC_ADDRESS: u8 = 0x20;
+const REMOTE_CONSOLE_ADDRESS: u8 = 0x81;
+const NETFN_CHASSIS: u8 = 0x00;
+const NETFN_APP: u8 = 0x06;
+const CHASSIS_POWER_UP: u8 = 0x01;
+const POWER_IS_ON: u8 = 0x01;
+
+// Look the user up by name only, rather than by name and privilege level
+const NAME_ONLY_LOOKUP: u8 = 0x10;
+const SIZE_RANDOM: usize = 16;
+const SIZE_GUID: usize = 16;
+const SIZE_KEY_CONSTANT: usize = 20;
+const SIZE_USER_KEY: usize = 20;
+pub const MAX_USERNAME_LENGTH: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum IpmiError {
+    #[error("Failed to reach the BMC: {0}")]
+    ConnectError(#[from] std::io::Error),
+
+    #[error("BMC did not answer")]
+    NoResponse,
+
+    #[error("Invalid response from the BMC")]
+    InvalidResponse,
+
+    #[error("BMC refused the session: {0}")]
+    SessionRejected(&'static str),
+
+    #[error("BMC login as {0} was rejected")]
+    AuthenticationFailed(String),
+
+    #[error("Unsupported cipher suite: {0}")]
+    UnsupportedCipherSuite(u8),
+
+    #[error("Username or password is too long")]
+    CredentialsTooLong,
+
+    #[error("{0} failed with completion code {1:#04x}")]
+    CommandFailed(&'static str, u8),
+
+    #[error("Session encryption failed: {0}")]
+    CryptoError(#[from] ErrorStack),
+}
+
+type Result<T> = std::result::Result<T, IpmiError>;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Privilege {
+    // The lowest level allowed to control the chassis
+    Operator = 0x03,
+    #[default]
+    Administrator = 0x04,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hash {
+    Sha1,
+    Sha256,
+}
+
+impl Hash {
+    fn digest(self) -> MessageDigest {
+        match self {
+            Hash::Sha1 => MessageDigest::sha1(),
+            Hash::Sha256 => MessageDigest::sha256(),
+        }
+    }
+
+    // RAKP-HMAC-SHA1 and RAKP-HMAC-SHA256
+    fn authentication_algorithm(self) -> u8 {
+        match self {
+            Hash::Sha1 => 0x01,
+            Hash::Sha256 => 0x03,
+        }
+    }
+
+    // HMAC-SHA1-96 and HMAC-SHA256-128
+    fn integrity_algorithm(self) -> u8 {
+        match self {
+            Hash::Sha1 => 0x01,
+            Hash::Sha256 => 0x04,
+        }
+    }
+
+    fn integrity_length(self) -> usize {
+        match self {
+            Hash::Sha1 => 12,
+            Hash::Sha256 => 16,
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let key = PKey::hmac(key)?;
+        let mut signer = Signer::new(self.digest(), &key)?;
+        signer.update(data)?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    // Truncated HMAC, used for the integrity of packets and in RAKP4
+    fn integrity_code(self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut code = self.hmac(key, data)?;
+        code.truncate(self.integrity_length());
+        Ok(code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CipherSuite {
+    hash: Hash,
+    integrity: bool,
+    confidentiality: bool,
+}
+
+// Only the suites that authenticate the user, suite 0 would let anyone in. The confidentiality
+// algorithm is always AES-CBC-128.
+pub fn cipher_suite(id: u8) -> Option<CipherSuite> {
+    let (hash, integrity, confidentiality) = match id {
+        1 => (Hash::Sha1, false, false),
+        2 => (Hash::Sha1, true, false),
+        3 => (Hash::Sha1, true, true),
+        15 => (Hash::Sha256, false, false),
+        16 => (Hash::Sha256, true, false),
+        17 => (Hash::Sha256, true, true),
+        _ => return None,
+    };
+    Some(CipherSuite {
+        hash,
+        integrity,
+        confidentiality,
+    })
+}
+
+// RMCP+ status codes (IPMI v2.0 table 13-15)
+fn status_message(status: u8) -> &'static str {
+    match status {
+        0x01 => "insufficient resources to create a session",
+        0x02 => "invalid session ID",
+        0x04 => "invalid authentication algorithm",
+        0x05 => "invalid integrity algorithm",
+        0x09 => "invalid role",
+        0x0A => "unauthorized role or privilege level requested",
+        0x0B => "insufficient resources to create a session at the requested role",
+        0x0C => "invalid name length",
+        0x0D => "unauthorized name",
+        0x0F => "invalid integrity check value",
+        0x10 => "invalid confidentiality algorithm",
+        0x11 => "no cipher suite match with proposed security algorithms",
+        _ => "unknown error",
+    }
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// Passwords are zero padded to 20 bytes to be used as the user's key (Kuid)
+fn user_key(password: &str) -> Result<Vec<u8>> {
+    if password.len() > SIZE_USER_KEY {
+        return Err(IpmiError::CredentialsTooLong);
+    }
+    let mut key = password.as_bytes().to_vec();
+    key.resize(SIZE_USER_KEY, 0);
+    Ok(key)
+}
+
+// Keys derived from the session integrity key (SIK): K1 for the integrity of packets, and the
+// first 16 bytes of K2 for their encryption
+struct Keys {
+    suite: CipherSuite,
+    k1: Vec<u8>,
+    k2: Vec<u8>,
+}
+
+impl Keys {
+    fn derive(suite: CipherSuite, sik: &[u8]) -> Result<Keys> {
+        Ok(Keys {
+            suite,
+            k1: suite.hash.hmac(sik, &[0x01; SIZE_KEY_CONSTANT])?,
+            k2: suite.hash.hmac(sik, &[0x02; SIZE_KEY_CONSTANT])?,
+        })
+    }
+}
+
+// AES-CBC-128 payload:
+// ----------------------------------------------------------------------
+// | IV       | Payload | Confidentiality Pad        | Pad Length |
+// ----------------------------------------------------------------------
+// | 16 bytes | n bytes | 0-15 bytes (01, 02, 03...) | 1 byte     |
+// ----------------------------------------------------------------------
+// Everything after the IV is encrypted, and padded to a multiple of 16 bytes.
+fn encrypt(key: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let iv: [u8; 16] = random()?;
+    let pad_length = (16 - (payload.len() + 1) % 16) % 16;
+    let mut plain = payload.to_vec();
+    plain.extend(1..=pad_length as u8);
+    plain.push(pad_length as u8);
+
+    let mut crypter = Crypter::new(Cipher::aes_128_cbc(), Mode::Encrypt, &key[..16], Some(&iv))?;
+    crypter.pad(false);
+    let mut encrypted = vec![0u8; plain.len() + 16];
+    let written = crypter.update(&plain, &mut encrypted)?;
+    let written = written + crypter.finalize(&mut encrypted[written..])?;
+    encrypted.truncate(written);
+
+    Ok([iv.as_slice(), &encrypted].concat())
+}
+
+fn decrypt(key: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() < 32 || !payload.len().is_multiple_of(16) {
+        return Err(IpmiError::InvalidResponse);
+    }
+    let (iv, encrypted) = payload.split_at(16);
+
+    let mut crypter = Crypter::new(Cipher::aes_128_cbc(), Mode::Decrypt, &key[..16], Some(iv))?;
+    crypter.pad(false);
+    let mut plain = vec![0u8; encrypted.len() + 16];
+    let written = crypter.update(encrypted, &mut plain)?;
+    let written = written + crypter.finalize(&mut plain[written..])?;
+    plain.truncate(written);
+
+    let pad_length = *plain.last().unwrap() as usize;
+    if pad_length + 1 > plain.len() {
+        return Err(IpmiError::InvalidResponse);
+    }
+    plain.truncate(plain.len() - pad_length - 1);
+    Ok(plain)
+}
+
+// IPMI v2.0 RMCP+ packet:
+// -------------------------------------------------------------------------------------------------------------------------------------
+// | RMCP Header   | Auth Type | Payload Type | Session ID | Sequence | Length  | Payload | Integrity Pad | Pad Length | Next Header | AuthCode    |
+// | (06 00 FF 07) | (0x06)    |              | (LE)       | (LE)     | (LE)    |         | (0xFF)        |            | (0x07)      |             |
+// -------------------------------------------------------------------------------------------------------------------------------------
+// | 4 bytes       | 1 byte    | 1 byte       | 4 bytes    | 4 bytes  | 2 bytes | n bytes | 0-3 bytes     | 1 byte     | 1 byte      | 12/16 bytes |
+// -------------------------------------------------------------------------------------------------------------------------------------
+// - Payload Type: bit 7 is set when the payload is encrypted, bit 6 when the packet is authenticated.
+// - The trailer (from the integrity pad on) is only there for authenticated packets. The pad makes the
+//   data covered by the AuthCode, from the auth type to the next header, a multiple of 4 bytes.
+fn encode_packet(
+    payload_type: u8,
+    session_id: u32,
+    sequence: u32,
+    payload: &[u8],
+    keys: Option<&Keys>,
+) -> Result<Vec<u8>> {
+    let mut payload_type = payload_type;
+    let mut payload = payload.to_vec();
+    if let Some(keys) = keys {
+        if keys.suite.confidentiality {
+            payload = encrypt(&keys.k2, &payload)?;
+            payload_type |= PAYLOAD_ENCRYPTED;
+        }
+        if keys.suite.integrity {
+            payload_type |= PAYLOAD_AUTHENTICATED;
+        }
+    }
+
+    let mut packet = RMCP_HEADER.to_vec();
+    packet.push(AUTH_TYPE_RMCP_PLUS);
+    packet.push(payload_type);
+    packet.extend(session_id.to_le_bytes());
+    packet.extend(sequence.to_le_bytes());
+    packet.extend((payload.len() as u16).to_le_bytes());
+    packet.extend(&payload);
+
+    if let Some(keys) = keys.filter(|keys| keys.suite.integrity) {
+        let pad_length = (4 - (packet.len() - RMCP_HEADER.len() + 2) % 4) % 4;
+        packet.extend(std::iter::repeat_n(0xFF, pad_length));
+        packet.push(pad_length as u8);
+        packet.push(NEXT_HEADER);
+        let code = keys
+            .suite
+            .hash
+            .integrity_code(&keys.k1, &packet[RMCP_HEADER.len()..])?;
+        packet.extend(code);
+    }
+
+    Ok(packet)
+}
+
+struct Packet {
+    payload_type: u8,
+    session_id: u32,
+    payload: Vec<u8>,
+}
+
+fn decode_packet(packet: &[u8], keys: Option<&Keys>) -> Result<Packet> {
+    let header_end = RMCP_HEADER.len() + SIZE_SESSION_HEADER;
+    if packet.len() < header_end
+        || packet[0] != RMCP_HEADER[0]
+        || packet[3] != RMCP_HEADER[3]
+        || packet[4] != AUTH_TYPE_RMCP_PLUS
+    {
+        return Err(IpmiError::InvalidResponse);
+    }
+
+    let payload_type = packet[5];
+    let length = u16::from_le_bytes([packet[14], packet[15]]) as usize;
+    let mut payload = packet
+        .get(header_end..header_end + length)
+        .ok_or(IpmiError::InvalidResponse)?
+        .to_vec();
+
+    if let Some(keys) = keys {
+        if keys.suite.integrity {
+            let code_length = keys.suite.hash.integrity_length();
+            if payload_type & PAYLOAD_AUTHENTICATED == 0
+                || packet.len() < header_end + length + 2 + code_length
+            {
+                return Err(IpmiError::InvalidResponse);
+            }
+            let (data, code) = packet.split_at(packet.len() - code_length);
+            let expected = keys
+                .suite
+                .hash
+                .integrity_code(&keys.k1, &data[RMCP_HEADER.len()..])?;
+            if !memcmp::eq(code, &expected) {
+                return Err(IpmiError::InvalidResponse);
+            }
+        }
+        if payload_type & PAYLOAD_ENCRYPTED != 0 {
+            payload = decrypt(&keys.k2, &payload)?;
+        }
+    }
+
+    Ok(Packet {
+        payload_type: payload_type & 0x3F,
+        session_id: u32_at(packet, 6),
+        payload,
+    })
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)))
+}
+
+struct Command {
+    netfn: u8,
+    cmd: u8,
+    name: &'static str,
+}
+
+const GET_CHASSIS_STATUS: Command = Command {
+    netfn: NETFN_CHASSIS,
+    cmd: 0x01,
+    name: "Get Chassis Status",
+};
+
+const CHASSIS_CONTROL: Command = Command {
+    netfn: NETFN_CHASSIS,
+    cmd: 0x02,
+    name: "Chassis Control",
+};
+
+const SET_SESSION_PRIVILEGE: Command = Command {
+    netfn: NETFN_APP,
+    cmd: 0x3B,
+    name: "Set Session Privilege Level",
+};
+
+const CLOSE_SESSION: Command = Command {
+    netfn: NETFN_APP,
+    cmd: 0x3C,
+    name: "Close Session",
+};
+
+// IPMI LAN request:
+// --------------------------------------------------------------------------------------
+// | rsAddr | NetFn/rsLUN | Checksum | rqAddr | rqSeq/rqLUN | Cmd    | Data    | Checksum |
+// --------------------------------------------------------------------------------------
+// | 1 byte | 1 byte      | 1 byte   | 1 byte | 1 byte      | 1 byte | n bytes | 1 byte   |
+// --------------------------------------------------------------------------------------
+// Responses swap the addresses, add 1 to the NetFn, and start the data with a completion code.
+fn encode_request(command: &Command, rq_seq: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![BMC_ADDRESS, command.netfn << 2];
+    message.push(checksum(&message));
+    message.extend([REMOTE_CONSOLE_ADDRESS, rq_seq << 2, command.cmd]);
+    message.extend(data);
+    message.push(checksum(&message[3..]));
+    message
+}
+
+// None when the message is not the response to this request
+fn decode_response(message: &[u8], command: &Command, rq_seq: u8) -> Option<Result<Vec<u8>>> {
+    if message.len() < 8
+        || message[1] >> 2 != command.netfn + 1
+        || message[4] >> 2 != rq_seq
+        || message[5] != command.cmd
+        || checksum(&message[..3]) != 0
+        || checksum(&message[3..]) != 0
+    {
+        return None;
+    }
+    match message[6] {
+        0x00 => Some(Ok(message[7..message.len() - 1].to_vec())),
+        code => Some(Err(IpmiError::CommandFailed(command.name, code))),
+    }
+}
+
+// BMCs silently drop the requests they don't like, so a BMC that never answers might also have
+// been given the wrong cipher suite or privilege
+async fn exchange<T>(
+    socket: &UdpSocket,
+    request: impl FnMut() -> Result<Vec<u8>>,
+    accept: impl FnMut(&[u8]) -> Option<Result<T>>,
+) -> Result<T> {
+    udp::exchange(socket, request, accept)
+        .await?
+        .ok_or(IpmiError::NoResponse)
+}
+
+pub struct Login<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub privilege: Privilege,
+}
+
+struct Session {
+    socket: UdpSocket,
+    keys: Keys,
+    // Our session ID, which the BMC puts in its packets (SIDm)
+    console_id: u32,
+    // The BMC's session ID, which goes in ours (SIDc)
+    bmc_id: u32,
+    sequence: u32,
+    rq_seq: u8,
+}
+
+impl Session {
+    // Open Session Request, then the RAKP messages 1 to 4 that authenticate both sides and
+    // agree on the session keys (IPMI v2.0 section 13.20)
+    async fn open(socket: UdpSocket, login: &Login<'_>, suite: CipherSuite) -> Result<Session> {
+        if login.username.len() > MAX_USERNAME_LENGTH {
+            return Err(IpmiError::CredentialsTooLong);
+        }
+        let user_key = user_key(login.password)?;
+        let hash = suite.hash;
+        let console_id = u32::from_le_bytes(random()?).max(1);
+        let role = login.privilege as u8 | NAME_ONLY_LOOKUP;
+
+        let mut request = vec![0x00, login.privilege as u8, 0x00, 0x00];
+        request.extend(console_id.to_le_bytes());
+        let algorithms = [
+            hash.authentication_algorithm(),
+            if suite.integrity {
+                hash.integrity_algorithm()
+            } else {
+                0x00
+            },
+            if suite.confidentiality { 0x01 } else { 0x00 },
+        ];
+        for (kind, algorithm) in algorithms.into_iter().enumerate() {
+            request.extend([kind as u8, 0x00, 0x00, 0x08, algorithm, 0x00, 0x00, 0x00]);
+        }
+        let bmc_id = exchange(
+            &socket,
+            || encode_packet(PAYLOAD_OPEN_SESSION_REQUEST, 0, 0, &request, None),
+            |packet| {
+                let response = reply(packet, PAYLOAD_OPEN_SESSION_RESPONSE)?;
+                match check_status(&response, 36, console_id) {
+                    Ok(()) => Some(Ok(u32_at(&response, 8))),
+                    Err(e) => Some(Err(e)),
+                }
+            },
+        )
+        .await?;
+
+        let console_random: [u8; SIZE_RANDOM] = random()?;
+        let mut rakp1 = vec![0x00, 0x00, 0x00, 0x00];
+        rakp1.extend(bmc_id.to_le_bytes());
+        rakp1.extend(console_random);
+        rakp1.extend([role, 0x00, 0x00, login.username.len() as u8]);
+        rakp1.extend(login.username.as_bytes());
+        let rakp2 = exchange(
+            &socket,
+            || encode_packet(PAYLOAD_RAKP1, 0, 0, &rakp1, None),
+            |packet| {
+                let response = reply(packet, PAYLOAD_RAKP2)?;
+                let length = 8 + SIZE_RANDOM + SIZE_GUID + hash.digest().size();
+                Some(check_status(&response, length, console_id).map(|_| response))
+            },
+        )
+        .await?;
+        let bmc_random = &rakp2[8..8 + SIZE_RANDOM];
+        let bmc_guid = &rakp2[8 + SIZE_RANDOM..8 + SIZE_RANDOM + SIZE_GUID];
+
+        // The BMC proves that it knows the password too, a mismatch means it is not ours
+        let user = [
+            &[role, login.username.len() as u8],
+            login.username.as_bytes(),
+        ]
+        .concat();
+        let expected = hash.hmac(
+            &user_key,
+            &[
+                &console_id.to_le_bytes()[..],
+                &bmc_id.to_le_bytes(),
+                &console_random,
+                bmc_random,
+                bmc_guid,
+                &user,
+            ]
+            .concat(),
+        )?;
+        if !memcmp::eq(&rakp2[8 + SIZE_RANDOM + SIZE_GUID..], &expected) {
+            return Err(IpmiError::AuthenticationFailed(login.username.into()));
+        }
+
+        let mut rakp3 = vec![0x00, 0x00, 0x00, 0x00];
+        rakp3.extend(bmc_id.to_le_bytes());
+        rakp3.extend(hash.hmac(
+            &user_key,
+            &[bmc_random, &console_id.to_le_bytes(), &user].concat(),
+        )?);
+        let rakp4 = exchange(
+            &socket,
+            || encode_packet(PAYLOAD_RAKP3, 0, 0, &rakp3, None),
+            |packet| {
+                let response = reply(packet, PAYLOAD_RAKP4)?;
+                let length = 8 + hash.integrity_length();
+                Some(check_status(&response, length, console_id).map(|_| response))
+            },
+        )
+        .await?;
+
+        let sik = hash.hmac(&user_key, &[&console_random, bmc_random, &user].concat())?;
+        let expected = hash.integrity_code(
+            &sik,
+            &[&console_random[..], &bmc_id.to_le_bytes(), bmc_guid].concat(),
+        )?;
+        if !memcmp::eq(&rakp4[8..], &expected) {
+            return Err(IpmiError::InvalidResponse);
+        }
+
+        Ok(Session {
+            socket,
+            keys: Keys::derive(suite, &sik)?,
+            console_id,
+            bmc_id,
+            sequence: 0,
+            rq_seq: 0,
+        })
+    }
+
+    async fn command(&mut self, command: &Command, data: &[u8]) -> Result<Vec<u8>> {
+        self.rq_seq = (self.rq_seq + 1) % 64;
+        let message = encode_request(command, self.rq_seq, data);
+
+        let Session {
+            socket,
+            keys,
+            console_id,
+            bmc_id,
+            sequence,
+            rq_seq,
+        } = self;
+        exchange(
+            socket,
+            || {
+                // Retries get a new sequence number, or the BMC would take them for replays
+                *sequence += 1;
+                encode_packet(PAYLOAD_IPMI, *bmc_id, *sequence, &message, Some(keys))
+            },
+            |packet| {
+                let response = decode_packet(packet, Some(keys)).ok()?;
+                if response.payload_type != PAYLOAD_IPMI || response.session_id != *console_id {
+                    return None;
+                }
+                decode_response(&response.payload, command, *rq_seq)
+            },
+        )
+        .await
+    }
+
+    // Sessions only time out after a minute or so, and BMCs allow a handful of them at once
+    async fn close(mut self) {
+        let bmc_id = self.bmc_id.to_le_bytes();
+        let _ = self.command(&CLOSE_SESSION, &bmc_id).await;
+    }
+}
+
+// The payload of a pre-session reply of the given type
+fn reply(packet: &[u8], payload_type: u8) -> Option<Vec<u8>> {
+    let packet = decode_packet(packet, None).ok()?;
+    (packet.payload_type == payload_type).then_some(packet.payload)
+}
+
+// Replies to the session setup messages start with a message tag, a status code, and (after
+// 2 more bytes) our session ID
+fn check_status(response: &[u8], length: usize, console_id: u32) -> Result<()> {
+    match response.get(1) {
+        None => Err(IpmiError::InvalidResponse),
+        Some(0x00) if response.len() >= length && u32_at(response, 4) == console_id => Ok(()),
+        Some(0x00) => Err(IpmiError::InvalidResponse),
+        Some(status) => Err(IpmiError::SessionRejected(status_message(*status))),
+    }
+}
+
+async fn start_chassis(session: &mut Session, privilege: Privilege) -> Result<bool> {
+    // Sessions start at the User level, which can't control the chassis
+    session
+        .command(&SET_SESSION_PRIVILEGE, &[privilege as u8])
+        .await?;
+
+    let status = session.command(&GET_CHASSIS_STATUS, &[]).await?;
+    let power_state = status.first().ok_or(IpmiError::InvalidResponse)?;
+    if power_state & POWER_IS_ON != 0 {
+        return Ok(false);
+    }
+
+    session
+        .command(&CHASSIS_CONTROL, &[CHASSIS_POWER_UP])
+        .await?;
+    Ok(true)
+}
+
+// Powers the chassis up if it is off, and returns whether it had to
+pub async fn power_on(
+    host: &str,
+    port: u16,
+    login: &Login<'_>,
+    cipher_suite_id: u8,
+) -> Result<bool> {
+    let suite =
+        cipher_suite(cipher_suite_id).ok_or(IpmiError::UnsupportedCipherSuite(cipher_suite_id))?;
+    let socket = udp::connect(host, port).await?;
+    let mut session = Session::open(socket, login, suite).await?;
+    let started = start_chassis(&mut session, login.privilege).await;
+    session.close().await;
+    started
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const BMC_ID: u32 = 0x0A0B0C0D;
+    const BMC_GUID: [u8; SIZE_GUID] = [0x42; SIZE_GUID];
+
+    struct FakeBmc {
+        socket: UdpSocket,
+        password: &'static str,
+        powered: Arc<AtomicBool>,
+        console_id: u32,
+        suite: Option<CipherSuite>,
+        console_random: Vec<u8>,
+        bmc_random: Vec<u8>,
+        user: Vec<u8>,
+        keys: Option<Keys>,
+    }
+
+    impl FakeBmc {
+        async fn send(&self, payload_type: u8, session_id: u32, payload: &[u8]) {
+            let packet =
+                encode_packet(payload_type, session_id, 1, payload, self.keys.as_ref()).unwrap();
+            self.socket.send(&packet).await.unwrap();
+        }
+
+        async fn handle(&mut self, packet: &[u8]) {
+            let request = decode_packet(packet, self.keys.as_ref()).unwrap();
+            let payload = request.payload;
+            let user_key = user_key(self.password).unwrap();
+
+            match request.payload_type {
+                PAYLOAD_OPEN_SESSION_REQUEST => {
+                    self.console_id = u32_at(&payload, 4);
+                    let suite = [1, 2, 3, 15, 16, 17]
+                        .into_iter()
+                        .filter_map(cipher_suite)
+                        .find(|suite| {
+                            payload[12] == suite.hash.authentication_algorithm()
+                                && (payload[20] != 0) == suite.integrity
+                                && (payload[28] != 0) == suite.confidentiality
+                        })
+                        .unwrap();
+                    self.suite = Some(suite);
+
+                    let mut response = vec![payload[0], 0x00, payload[1], 0x00];
+                    response.extend(self.console_id.to_le_bytes());
+                    response.extend(BMC_ID.to_le_bytes());
+                    response.extend(&payload[8..32]);
+                    self.send(PAYLOAD_OPEN_SESSION_RESPONSE, 0, &response).await;
+                }
+                PAYLOAD_RAKP1 => {
+                    let hash = self.suite.unwrap().hash;
+                    self.console_random = payload[8..24].to_vec();
+                    self.bmc_random = vec![0x24; SIZE_RANDOM];
+                    let length = payload[27] as usize;
+                    self.user = [&[payload[24], payload[27]], &payload[28..28 + length]].concat();
+
+                    let mut response = vec![payload[0], 0x00, 0x00, 0x00];
+                    response.extend(self.console_id.to_le_bytes());
+                    response.extend(&self.bmc_random);
+                    response.extend(BMC_GUID);
+                    let data = [
+                        &self.console_id.to_le_bytes()[..],
+                        &BMC_ID.to_le_bytes(),
+                        &self.console_random,
+                        &self.bmc_random,
+                        &BMC_GUID,
+                        &self.user,
+                    ]
+                    .concat();
+                    response.extend(hash.hmac(&user_key, &data).unwrap());
+                    self.send(PAYLOAD_RAKP2, 0, &response).await;
+                }
+                PAYLOAD_RAKP3 => {
+                    let suite = self.suite.unwrap();
+                    let data = [
+                        &self.bmc_random[..],
+                        &self.console_id.to_le_bytes(),
+                        &self.user,
+                    ]
+                    .concat();
+                    assert_eq!(payload[8..], suite.hash.hmac(&user_key, &data).unwrap());
+
+                    let data = [&self.console_random[..], &self.bmc_random, &self.user].concat();
+                    let sik = suite.hash.hmac(&user_key, &data).unwrap();
+                    let mut response = vec![payload[0], 0x00, 0x00, 0x00];
+                    response.extend(self.console_id.to_le_bytes());
+                    let data =
+                        [&self.console_random[..], &BMC_ID.to_le_bytes(), &BMC_GUID].concat();
+                    response.extend(suite.hash.integrity_code(&sik, &data).unwrap());
+                    self.send(PAYLOAD_RAKP4, 0, &response).await;
+                    self.keys = Some(Keys::derive(suite, &sik).unwrap());
+                }
+                PAYLOAD_IPMI => {
+                    assert_eq!(request.session_id, BMC_ID);
+                    let (netfn, rq_seq, cmd) = (payload[1] >> 2, payload[4] >> 2, payload[5]);
+                    let data = &payload[6..payload.len() - 1];
+                    let reply = match (netfn, cmd) {
+                        (NETFN_APP, 0x3B) => vec![data[0]],
+                        (NETFN_APP, 0x3C) => vec![],
+                        (NETFN_CHASSIS, 0x01) => {
+                            vec![self.powered.load(Ordering::SeqCst) as u8, 0x00, 0x00]
+                        }
+                        (NETFN_CHASSIS, 0x02) => {
+                            assert_eq!(data, [CHASSIS_POWER_UP]);
+                            self.powered.store(true, Ordering::SeqCst);
+                            vec![]
+                        }
+                        _ => panic!("unexpected command {:#04x}", cmd),
+                    };
+
+                    let mut response = vec![REMOTE_CONSOLE_ADDRESS, (netfn + 1) << 2];
+                    response.push(checksum(&response));
+                    response.extend([BMC_ADDRESS, rq_seq << 2, cmd, 0x00]);
+                    response.extend(reply);
+                    response.push(checksum(&response[3..]));
+                    self.send(PAYLOAD_IPMI, self.console_id, &response).await;
+                }
+                other => panic!("unexpected payload type {:#04x}", other),
+            }
+        }
+    }
+
+    // Answers a single session at a time, like a BMC would on a quiet network
+    async fn spawn_bmc(password: &'static str, powered: bool) -> (u16, Arc<AtomicBool>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let powered = Arc::new(AtomicBool::new(powered));
+        let state = powered.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let (received, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            socket.connect(peer).await.unwrap();
+            let mut bmc = FakeBmc {
+                socket,
+                password,
+                powered: state,
+                console_id: 0,
+                suite: None,
+                console_random: vec![],
+                bmc_random: vec![],
+                user: vec![],
+                keys: None,
+            };
+            bmc.handle(&buffer[..received]).await;
+            loop {
+                let received = bmc.socket.recv(&mut buffer).await.unwrap();
+                bmc.handle(&buffer[..received]).await;
+            }
+        });
+        (port, powered)
+    }
+
+    fn login(password: &'static str) -> Login<'static> {
+        Login {
+            username: "admin",
+            password,
+            privilege: Privilege::Operator,
+        }
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        assert!(cipher_suite(0).is_none());
+        assert!(cipher_suite(4).is_none());
+        assert_eq!(
+            cipher_suite(17),
+            Some(CipherSuite {
+                hash: Hash::Sha256,
+                integrity: true,
+                confidentiality: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let key = [0x11; 20];
+        for length in [0, 15, 16, 40] {
+            let payload = vec![0xAB; length];
+            let encrypted = encrypt(&key, &payload).unwrap();
+            assert_eq!(encrypted.len() % 16, 0);
+            assert_eq!(decrypt(&key, &encrypted).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn test_authenticated_packet() {
+        let keys = Keys::derive(cipher_suite(3).unwrap(), &[0x33; 20]).unwrap();
+        let message = encode_request(&GET_CHASSIS_STATUS, 1, &[]);
+        let packet = encode_packet(PAYLOAD_IPMI, BMC_ID, 7, &message, Some(&keys)).unwrap();
+        assert_eq!((packet.len() - RMCP_HEADER.len() - 12) % 4, 0);
+        assert_eq!(
+            packet[5],
+            PAYLOAD_IPMI | PAYLOAD_ENCRYPTED | PAYLOAD_AUTHENTICATED
+        );
+
+        let decoded = decode_packet(&packet, Some(&keys)).unwrap();
+        assert_eq!(decoded.session_id, BMC_ID);
+        assert_eq!(decoded.payload, message);
+
+        let mut tampered = packet.clone();
+        tampered[10] ^= 0x01;
+        assert!(decode_packet(&tampered, Some(&keys)).is_err());
+    }
+
+    #[test]
+    fn test_encode_request() {
+        // Get Chassis Status, as sent by ipmitool
+        assert_eq!(
+            encode_request(&GET_CHASSIS_STATUS, 1, &[]),
+            vec![0x20, 0x00, 0xE0, 0x81, 0x04, 0x01, 0x7A]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_power_on() {
+        for suite in [3, 17, 1] {
+            let (port, powered) = spawn_bmc("hunter2", false).await;
+            let started = power_on("127.0.0.1", port, &login("hunter2"), suite).await;
+            assert!(started.unwrap(), "cipher suite {}", suite);
+            assert!(powered.load(Ordering::SeqCst));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_power_on_already_on() {
+        let (port, powered) = spawn_bmc("hunter2", true).await;
+        let started = power_on("127.0.0.1", port, &login("hunter2"), 3).await;
+        assert!(!started.unwrap());
+        assert!(powered.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password() {
+        let (port, powered) = spawn_bmc("hunter2", false).await;
+        let started = power_on("127.0.0.1", port, &login("calvin"), 3).await;
+        assert!(matches!(started, Err(IpmiError::AuthenticationFailed(_))));
+        assert!(!powered.load(Ordering::SeqCst));
+    }
+}
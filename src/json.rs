@@ -0,0 +1,245 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum JsonPathError {
+    #[error("Invalid JSON path: {0}")]
+    InvalidPath(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonCondition {
+    Equals(Value),
+    NotEquals(Value),
+    GreaterThan(f64),
+    AtLeast(f64),
+    LessThan(f64),
+    AtMost(f64),
+    // Whether the path should be there at all, whatever its value
+    Exists(bool),
+    // Only for strings
+    Matches(#[serde(with = "serde_regex")] Regex),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonAssertion {
+    pub path: String,
+    #[serde(flatten)]
+    pub condition: JsonCondition,
+}
+
+// Paths are keys separated by dots, with array indices in brackets, e.g. `nodes[0].status`.
+// A leading `$.` is allowed but not needed.
+fn parse_path(path: &str) -> Result<Vec<Segment>, JsonPathError> {
+    let invalid = || JsonPathError::InvalidPath(path.to_string());
+    let trimmed = path.strip_prefix("$.").unwrap_or(path);
+
+    let mut segments = Vec::new();
+    for (i, part) in trimmed.split('.').enumerate() {
+        let (key, mut indices) = match part.find('[') {
+            Some(start) => (&part[..start], &part[start..]),
+            None => (part, ""),
+        };
+        // Only the first part can be an index on its own, for documents that are arrays
+        if key.is_empty() && (i > 0 || indices.is_empty()) {
+            return Err(invalid());
+        }
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_string()));
+        }
+
+        while !indices.is_empty() {
+            let end = indices.find(']').ok_or_else(invalid)?;
+            let index = indices[1..end].parse::<usize>().map_err(|_| invalid())?;
+            segments.push(Segment::Index(index));
+            indices = &indices[end + 1..];
+            if !indices.is_empty() && !indices.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+fn lookup<'a>(value: &'a Value, segments: &[Segment]) -> Option<&'a Value> {
+    segments
+        .iter()
+        .try_fold(value, |value, segment| match segment {
+            Segment::Key(key) => value.get(key),
+            Segment::Index(index) => value.get(index),
+        })
+}
+
+// YAML and JSON can disagree on whether a number is an integer (1 vs 1.0)
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl JsonAssertion {
+    pub fn validate(&self) -> Result<(), JsonPathError> {
+        parse_path(&self.path).map(|_| ())
+    }
+
+    // Describes what didn't hold, with the failing path
+    pub fn check(&self, document: &Value) -> Result<(), String> {
+        let segments = parse_path(&self.path).map_err(|e| e.to_string())?;
+        let found = lookup(document, &segments);
+
+        let compare = |description: &str, expected: f64, holds: fn(f64, f64) -> bool| match found
+            .and_then(Value::as_f64)
+        {
+            Some(actual) if holds(actual, expected) => Ok(()),
+            Some(actual) => Err(format!(
+                "{}: expected {} {}, got {}",
+                self.path, description, expected, actual
+            )),
+            None => Err(format!("{}: expected a number", self.path)),
+        };
+
+        match &self.condition {
+            JsonCondition::Exists(expected) => {
+                if found.is_some() == *expected {
+                    Ok(())
+                } else if *expected {
+                    Err(format!("{}: not found", self.path))
+                } else {
+                    Err(format!("{}: should not exist", self.path))
+                }
+            }
+            _ if found.is_none() => Err(format!("{}: not found", self.path)),
+            JsonCondition::Equals(expected) => {
+                let actual = found.unwrap();
+                if values_equal(actual, expected) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "{}: expected {}, got {}",
+                        self.path, expected, actual
+                    ))
+                }
+            }
+            JsonCondition::NotEquals(unexpected) => {
+                if values_equal(found.unwrap(), unexpected) {
+                    Err(format!("{}: should not be {}", self.path, unexpected))
+                } else {
+                    Ok(())
+                }
+            }
+            JsonCondition::GreaterThan(expected) => compare(">", *expected, |a, b| a > b),
+            JsonCondition::AtLeast(expected) => compare(">=", *expected, |a, b| a >= b),
+            JsonCondition::LessThan(expected) => compare("<", *expected, |a, b| a < b),
+            JsonCondition::AtMost(expected) => compare("<=", *expected, |a, b| a <= b),
+            JsonCondition::Matches(regex) => match found.and_then(Value::as_str) {
+                Some(actual) if regex.is_match(actual) => Ok(()),
+                Some(actual) => Err(format!(
+                    "{}: {:?} does not match {}",
+                    self.path, actual, regex
+                )),
+                None => Err(format!("{}: expected a string", self.path)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assertion(yaml: &str) -> JsonAssertion {
+        serde_yaml_ng::from_str(yaml).expect("Failed to parse YAML")
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("cluster.quorate").unwrap(),
+            vec![
+                Segment::Key("cluster".into()),
+                Segment::Key("quorate".into())
+            ]
+        );
+        assert_eq!(
+            parse_path("$.nodes[1][0].name").unwrap(),
+            vec![
+                Segment::Key("nodes".into()),
+                Segment::Index(1),
+                Segment::Index(0),
+                Segment::Key("name".into())
+            ]
+        );
+        assert_eq!(
+            parse_path("[2].status").unwrap(),
+            vec![Segment::Index(2), Segment::Key("status".into())]
+        );
+
+        for invalid in ["", "a..b", "a.", "nodes[x]", "nodes[0", "nodes[0]x"] {
+            assert!(
+                parse_path(invalid).is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let document = json!({
+            "status": "healthy",
+            "cluster": {"quorate": 1, "nodes": [{"name": "pve1", "load": 0.5}]},
+        });
+
+        for passing in [
+            "{path: status, equals: healthy}",
+            "{path: cluster.quorate, equals: 1.0}",
+            "{path: cluster.quorate, not_equals: 0}",
+            "{path: 'cluster.nodes[0].load', less_than: 1}",
+            "{path: 'cluster.nodes[0].load', at_least: 0.5}",
+            "{path: 'cluster.nodes[0].name', matches: '^pve'}",
+            "{path: cluster.nodes, exists: true}",
+            "{path: cluster.maintenance, exists: false}",
+        ] {
+            assert!(assertion(passing).check(&document).is_ok(), "{}", passing);
+        }
+
+        let failures = [
+            (
+                "{path: status, equals: degraded}",
+                "status: expected \"degraded\", got \"healthy\"",
+            ),
+            (
+                "{path: cluster.quorate, greater_than: 1}",
+                "cluster.quorate: expected > 1, got 1",
+            ),
+            ("{path: status, at_most: 1}", "status: expected a number"),
+            (
+                "{path: 'cluster.nodes[1].name', equals: pve2}",
+                "cluster.nodes[1].name: not found",
+            ),
+            (
+                "{path: cluster, exists: false}",
+                "cluster: should not exist",
+            ),
+            (
+                "{path: cluster.quorate, matches: '1'}",
+                "cluster.quorate: expected a string",
+            ),
+        ];
+        for (failing, message) in failures {
+            assert_eq!(assertion(failing).check(&document).unwrap_err(), message);
+        }
+    }
+}
@@ -1,6 +1,27 @@
+mod dns;
+mod http;
+mod ipmi;
+mod json;
+mod nfs;
+mod ping;
+mod plug;
+mod plugin;
+mod power;
+mod process_group;
+mod proxmox;
+mod redfish;
+mod scheduler;
 mod servers;
+mod shutdown;
+mod smb;
+mod snmp;
+mod ssh;
+mod tcp;
+mod tls;
+mod udp;
 mod wol;
 
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
 use colored::*;
 use crossterm::{
     execute,
@@ -8,11 +29,19 @@ use crossterm::{
     terminal::{Clear, ClearType},
 };
 use std::io::{stdout, Write};
-use std::{env, sync::Arc};
+use std::sync::Arc;
 use tokio::{sync::RwLock, time::sleep};
 
 const SPINNER: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
 
+// Messages are kept to one short line, since the status display redraws a fixed number of lines
+fn format_message(message: &Option<String>) -> ColoredString {
+    match message {
+        Some(message) => format!(" ({})", plugin::single_line(message)).dimmed(),
+        None => "".normal(),
+    }
+}
+
 fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrack: u16) -> u16 {
     let mut stdout = stdout();
 
@@ -32,17 +61,32 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
         // Display the server name and status
         let (icon, server_status) = match server.status {
             servers::ServerStatus::Waiting => ("◉".normal(), "waiting".normal()),
-            servers::ServerStatus::WOLSent => ("◉".yellow(), "WOL sent".yellow()),
+            servers::ServerStatus::PowerOnSent => match server.power_on {
+                None | Some(power::PowerOn::Wol(_)) => ("◉".yellow(), "WOL sent".yellow()),
+                Some(_) => ("◉".yellow(), "power-on sent".yellow()),
+            },
+            servers::ServerStatus::AlreadyOn => ("◉".yellow(), "already on".yellow()),
             servers::ServerStatus::Ok => ("◉".green(), "ok".green()),
+            servers::ServerStatus::AlreadyUp => ("◉".green(), "already up".green()),
             servers::ServerStatus::TimedOut => ("◉".red(), "timed-out".red()),
+            servers::ServerStatus::Failed => ("◉".red(), "failed".red()),
+            servers::ServerStatus::Skipped => ("◉".dimmed(), "skipped".dimmed()),
+            servers::ServerStatus::ShutdownSent => ("◉".yellow(), "shutdown sent".yellow()),
+            servers::ServerStatus::Down => ("◉".blue(), "down".blue()),
+        };
+        let resends = if server.resends > 0 {
+            format!(" (resent {}x)", server.resends).dimmed()
+        } else {
+            "".normal()
         };
         execute!(
             stdout,
             Print(format!(
-                "{} {}: {}\n",
+                "{} {}: {}{}\n",
                 icon,
                 server.name.bold(),
-                server_status
+                server_status,
+                resends
             ))
         )
         .unwrap();
@@ -61,10 +105,11 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
                     execute!(
                         stdout,
                         Print(format!(
-                            " {}\n{}    └── Status: {}\n",
+                            " {}\n{}    └── Status: {}{}\n",
                             check,
                             extension,
-                            "waiting".yellow()
+                            "waiting".yellow(),
+                            format_message(&check.message)
                         ))
                     )
                     .unwrap();
@@ -73,10 +118,11 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
                     execute!(
                         stdout,
                         Print(format!(
-                            " {}\n{}    └── Status: {}\n",
+                            " {}\n{}    └── Status: {}{}\n",
                             check,
                             extension,
-                            "timed-out".red()
+                            "timed-out".red(),
+                            format_message(&check.message)
                         ))
                     )
                     .unwrap();
@@ -87,8 +133,11 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
                     execute!(
                         stdout,
                         Print(format!(
-                            " {}\n{}   └── Status: {}\n",
-                            check, extension, spinner
+                            " {}\n{}   └── Status: {}{}\n",
+                            check,
+                            extension,
+                            spinner,
+                            format_message(&check.message)
                         ))
                     )
                     .unwrap();
@@ -97,10 +146,11 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
                     execute!(
                         stdout,
                         Print(format!(
-                            "{}\n{}   └── Status: {}\n",
+                            "{}\n{}   └── Status: {}{}\n",
                             check,
                             extension,
-                            "ok".green()
+                            "ok".green(),
+                            format_message(&check.message)
                         ))
                     )
                     .unwrap();
@@ -131,26 +181,125 @@ async fn update_server_status(servers: Arc<RwLock<Vec<servers::Server>>>) {
     }
 }
 
-fn print_help() {
-    println!("Usage: rallyup <file>");
-    println!("rallyup: A tool to send Wake-on-LAN packets to servers in dependency order");
+#[derive(Parser)]
+#[command(
+    version,
+    about = "A tool to send Wake-on-LAN packets to servers in dependency order",
+    arg_required_else_help = true,
+    override_usage = "rallyup [OPTIONS] <COMMAND>\n       rallyup <CONFIG_FILE>"
+)]
+struct Cli {
+    /// Path to the server configuration file
+    #[arg(short, long, global = true, default_value = "servers.yaml")]
+    config: String,
+
+    /// Wake up every server in the configuration file, same as `up`
+    #[arg(value_name = "CONFIG_FILE", conflicts_with = "config")]
+    legacy_config: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let args: Vec<String> = env::args().collect();
+impl Cli {
+    // Returns the configuration file and the command to run it with
+    fn resolve(self) -> Result<(String, Command), clap::Error> {
+        match (self.command, self.legacy_config) {
+            (Some(command), None) => Ok((self.config, command)),
+            // `rallyup <config>` from before there were commands
+            (None, Some(config)) => Ok((
+                config,
+                Command::Up {
+                    servers: Vec::new(),
+                    on_failure: scheduler::FailurePolicy::default(),
+                },
+            )),
+            (Some(_), Some(config)) => Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "use `-c {}` to run a command with a configuration file",
+                    config
+                ),
+            )),
+            (None, None) => Err(Cli::command().error(
+                ErrorKind::MissingSubcommand,
+                "a command or a configuration file is required",
+            )),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Wake up the servers in dependency order
+    Up {
+        /// Only wake these servers and the servers they depend on
+        servers: Vec<String>,
 
-    if args.len() != 2 {
-        print_help();
-        return Ok(());
+        /// What to do when a server fails to come up
+        #[arg(long, value_enum, default_value_t)]
+        on_failure: scheduler::FailurePolicy,
+    },
+    /// Shut the servers down in the reverse order
+    Down {
+        /// What to do when a server fails to shut down
+        #[arg(long, value_enum, default_value_t)]
+        on_failure: scheduler::FailurePolicy,
+    },
+    /// Keep the servers up, waking them up again whenever their health checks fail
+    Watch {
+        /// How long to wait between rounds of health checks
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "1m")]
+        interval: std::time::Duration,
+    },
+    /// Run every health check once and report the results
+    Check,
+    /// Validate the configuration file
+    Validate,
+    /// Show the order that the servers will be woken up in
+    Plan,
+    /// Power on a single server, ignoring its dependencies and health checks
+    Wake {
+        /// Name of the server to power on
+        name: String,
+    },
+}
+
+fn print_summary(servers: &[servers::Server]) {
+    let failures: Vec<&servers::Server> = servers.iter().filter(|s| s.failure.is_some()).collect();
+    if failures.is_empty() {
+        return;
     }
 
-    let filename = &args[1];
+    println!("{}", "Summary:".bold());
+    for server in failures {
+        let status = if server.status == servers::ServerStatus::Skipped {
+            "skipped".dimmed()
+        } else {
+            "failed".red()
+        };
+        println!(
+            "  {} {} ({})",
+            server.name.bold(),
+            status,
+            server.failure.as_deref().unwrap_or_default()
+        );
+    }
+    println!();
+}
 
-    let wake_order = servers::parse_server_dependencies(filename)?;
+enum Mode {
+    Up(scheduler::FailurePolicy),
+    Down(scheduler::FailurePolicy),
+    Watch(std::time::Duration),
+}
 
+async fn run_servers(
+    servers_in_order: Vec<servers::Server>,
+    mode: Mode,
+) -> Result<(), anyhow::Error> {
     let mut line_count = 0;
-    for server in wake_order.iter() {
+    for server in servers_in_order.iter() {
         // server status line
         line_count += 1;
         // 2 lines per health check
@@ -161,32 +310,208 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Need to keep it in a Arc<RwLock> since the status render loop will be reading
     // the server status while the health checks may be updating it concurrently
-    let servers = Arc::new(RwLock::new(wake_order.clone()));
+    let servers = Arc::new(RwLock::new(servers_in_order));
 
-    tokio::spawn(update_server_status(servers.clone()));
+    let render_task = tokio::spawn(update_server_status(servers.clone()));
 
-    for (server_index, server) in wake_order.into_iter().enumerate() {
-        wol::send_wol_packet(&server.mac, &server.interface, server.vlan)?;
-        {
-            let mut servers = servers.write().await;
-            servers[server_index].status = servers::ServerStatus::WOLSent;
+    let result = match mode {
+        Mode::Up(policy) => scheduler::wake_servers(servers.clone(), policy).await,
+        Mode::Down(policy) => scheduler::shut_down_servers(servers.clone(), policy).await,
+        Mode::Watch(interval) => scheduler::watch_servers(servers.clone(), interval).await,
+    };
+
+    render_task.abort();
+    {
+        let servers = servers.read().await;
+        render_servers(&servers, 0, line_count);
+        print_summary(&servers);
+    }
+    result
+}
+
+async fn check_servers(servers_in_order: Vec<servers::Server>) -> Result<(), anyhow::Error> {
+    let mut failed = 0;
+
+    // Servers are checked all at once, then printed in order
+    let tasks: Vec<_> = servers_in_order
+        .into_iter()
+        .map(|server| {
+            tokio::spawn(async move {
+                let mut results = Vec::new();
+                for check in &server.check {
+                    results.push(check.attempt_once().await);
+                }
+                (server, results)
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let (server, results) = task.await?;
+        if server.check.is_empty() {
+            println!(
+                "{} {}: {}\n",
+                "◉".dimmed(),
+                server.name.bold(),
+                "no checks".dimmed()
+            );
+            continue;
         }
 
-        let server_status = servers::perform_health_checks(servers.clone(), server_index).await;
+        let icon = if results.iter().all(|result| result.passed) {
+            "◉".green()
+        } else {
+            "◉".red()
+        };
+        println!("{} {}", icon, server.name.bold());
 
-        if let servers::ServerStatus::TimedOut = server_status {
-            let servers = servers.read().await;
-            render_servers(&servers, 0, line_count);
-            return Err(anyhow::anyhow!(
-                "health check for {} timed out",
-                server.name
-            ));
+        for (i, (check, result)) in server.check.iter().zip(results).enumerate() {
+            let branch = if i == server.check.len() - 1 {
+                "└──"
+            } else {
+                "├──"
+            };
+            let status = if result.passed {
+                "ok".green()
+            } else {
+                "failed".red()
+            };
+            println!(
+                "{} {}: {}{}",
+                branch,
+                check,
+                status,
+                format_message(&result.message)
+            );
+            if !result.passed {
+                failed += 1;
+            }
         }
+        println!();
     }
 
-    {
-        let servers = servers.read().await;
-        render_servers(&servers, 0, line_count);
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} health check(s) failed", failed));
+    }
+    Ok(())
+}
+
+fn print_plan(servers_in_order: &[servers::Server]) {
+    for (i, server) in servers_in_order.iter().enumerate() {
+        if server.depends.is_empty() {
+            println!("{}. {}", i + 1, server.name.bold());
+        } else {
+            println!(
+                "{}. {} (after {})",
+                i + 1,
+                server.name.bold(),
+                server
+                    .depends
+                    .iter()
+                    .map(|dep| dep.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let (config, command) = Cli::parse().resolve().unwrap_or_else(|e| e.exit());
+
+    let wake_order = servers::parse_server_dependencies(&config)?;
+
+    match command {
+        Command::Up {
+            servers,
+            on_failure,
+        } => {
+            let wake_order = if servers.is_empty() {
+                wake_order
+            } else {
+                servers::select_servers(&wake_order, &servers)?
+            };
+            run_servers(wake_order, Mode::Up(on_failure)).await
+        }
+        Command::Down { on_failure } => {
+            run_servers(servers::shutdown_plan(wake_order)?, Mode::Down(on_failure)).await
+        }
+        Command::Watch { interval } => run_servers(wake_order, Mode::Watch(interval)).await,
+        Command::Check => check_servers(wake_order).await,
+        Command::Validate => {
+            println!(
+                "{}: {} servers, configuration is valid",
+                config,
+                wake_order.len()
+            );
+            Ok(())
+        }
+        Command::Plan => {
+            print_plan(&wake_order);
+            Ok(())
+        }
+        Command::Wake { name } => {
+            let server = wake_order
+                .iter()
+                .find(|server| server.name == name)
+                .ok_or_else(|| servers::ServerConfigError::UnknownServer(name.clone()))?;
+            if server.power_on().await? {
+                println!(
+                    "Powered on {} with {}",
+                    server.name.bold(),
+                    server.power_on_method()
+                );
+            } else {
+                println!(
+                    "{} is already on according to {}",
+                    server.name.bold(),
+                    server.power_on_method()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str]) -> Result<(String, Command), clap::Error> {
+        Cli::try_parse_from(args)?.resolve()
+    }
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(&*format_message(&None), "");
+        let message = Some("JSON assertion failed:\n{\n  \"status\": \"degraded\"\n}".into());
+        assert_eq!(&*format_message(&message), " (JSON assertion failed:)");
+        let message = Some("x".repeat(200));
+        assert_eq!(format_message(&message).chars().count(), 83);
+    }
+
+    #[test]
+    fn test_cli() {
+        let (config, command) = resolve(&["rallyup", "-c", "lab.yaml", "up", "nas"]).unwrap();
+        assert_eq!(config, "lab.yaml");
+        assert!(matches!(command, Command::Up { servers, .. } if servers == ["nas"]));
+
+        let (config, command) = resolve(&["rallyup", "check"]).unwrap();
+        assert_eq!(config, "servers.yaml");
+        assert!(matches!(command, Command::Check));
+
+        // The positional form wakes up everything
+        let (config, command) = resolve(&["rallyup", "lab.yaml"]).unwrap();
+        assert_eq!(config, "lab.yaml");
+        assert!(matches!(
+            command,
+            Command::Up { servers, on_failure: scheduler::FailurePolicy::FailFast }
+                if servers.is_empty()
+        ));
+
+        assert!(resolve(&["rallyup", "-c", "lab.yaml"]).is_err());
+        assert!(resolve(&["rallyup", "-c", "lab.yaml", "other.yaml"]).is_err());
+        assert!(resolve(&["rallyup", "lab.yaml", "up"]).is_err());
     }
-    return Ok(());
 }
@@ -0,0 +1,365 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const PROGRAM_PORTMAP: u32 = 100000;
+const PROGRAM_NFS: u32 = 100003;
+const PROGRAM_MOUNT: u32 = 100005;
+const VERSION_PORTMAP: u32 = 2;
+const VERSION_NFS: u32 = 3;
+const VERSION_MOUNT: u32 = 3;
+const PROCEDURE_GETPORT: u32 = 3;
+const PROCEDURE_EXPORT: u32 = 5;
+const PROTOCOL_TCP: u32 = 6;
+
+const RPC_VERSION: u32 = 2;
+const MESSAGE_CALL: u32 = 0;
+const MESSAGE_REPLY: u32 = 1;
+const REPLY_ACCEPTED: u32 = 0;
+const ACCEPT_SUCCESS: u32 = 0;
+const LAST_FRAGMENT: u32 = 0x8000_0000;
+// Record markers can claim up to 2 GiB per fragment, export lists are nowhere near that
+const MAX_REPLY_LENGTH: usize = 1024 * 1024;
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ONC RPC Call Layout (RFC 5531), every field is a big endian u32 in XDR:
+// --------------------------------------------------------------------------------------------
+// | XID | Type (0) | RPC Version (2) | Program | Version | Procedure | Cred | Verifier | Args |
+// --------------------------------------------------------------------------------------------
+// The credential and verifier are a flavor followed by a length prefixed body, we always send
+// AUTH_NONE with an empty body. Over TCP, every message is preceded by a record marker with
+// the length of the fragment, and the high bit set on the last fragment.
+//
+// Accepted Reply Layout:
+// ------------------------------------------------------------------------------
+// | XID | Type (1) | Reply Status (0) | Verifier | Accept Status (0) | Results |
+// ------------------------------------------------------------------------------
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+struct XdrReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> XdrReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        XdrReader { data, offset: 0 }
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + 4)
+            .ok_or_else(|| invalid_data("truncated RPC message"))?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_opaque(&mut self) -> io::Result<&'a [u8]> {
+        let length = self.read_u32()? as usize;
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + length)
+            .ok_or_else(|| invalid_data("truncated RPC message"))?;
+        // Opaque data is padded to a multiple of 4 bytes
+        self.offset += length.div_ceil(4) * 4;
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.read_opaque()?).into_owned())
+    }
+}
+
+fn create_call(xid: u32, program: u32, version: u32, procedure: u32, args: &[u8]) -> Vec<u8> {
+    let mut call = Vec::with_capacity(40 + args.len());
+    for value in [
+        xid,
+        MESSAGE_CALL,
+        RPC_VERSION,
+        program,
+        version,
+        procedure,
+        // AUTH_NONE credential and verifier
+        0,
+        0,
+        0,
+        0,
+    ] {
+        call.extend_from_slice(&value.to_be_bytes());
+    }
+    call.extend_from_slice(args);
+    call
+}
+
+// Returns a reader positioned at the start of the procedure results
+fn parse_reply(message: &[u8], xid: u32) -> io::Result<XdrReader<'_>> {
+    let mut reader = XdrReader::new(message);
+    if reader.read_u32()? != xid {
+        return Err(invalid_data("RPC reply XID does not match the call"));
+    }
+    if reader.read_u32()? != MESSAGE_REPLY {
+        return Err(invalid_data("RPC message is not a reply"));
+    }
+    if reader.read_u32()? != REPLY_ACCEPTED {
+        return Err(invalid_data("RPC call was denied"));
+    }
+    // Verifier flavor and body
+    reader.read_u32()?;
+    reader.read_opaque()?;
+    if reader.read_u32()? != ACCEPT_SUCCESS {
+        return Err(invalid_data("RPC call was not successful"));
+    }
+    Ok(reader)
+}
+
+fn parse_export_list(reader: &mut XdrReader) -> io::Result<Vec<String>> {
+    let mut exports = Vec::new();
+    // Linked list of exports, each entry preceded by a "value follows" boolean
+    while reader.read_u32()? == 1 {
+        exports.push(reader.read_string()?);
+        // Groups allowed to mount the export, which we don't care about
+        while reader.read_u32()? == 1 {
+            reader.read_opaque()?;
+        }
+    }
+    Ok(exports)
+}
+
+async fn call(
+    address: SocketAddr,
+    program: u32,
+    version: u32,
+    procedure: u32,
+    args: &[u8],
+) -> io::Result<Vec<u8>> {
+    let xid = std::time::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .subsec_nanos();
+    let call = create_call(xid, program, version, procedure, args);
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        let mut request = (LAST_FRAGMENT | call.len() as u32).to_be_bytes().to_vec();
+        request.extend_from_slice(&call);
+        stream.write_all(&request).await?;
+        read_record(&mut stream).await
+    };
+    let reply = tokio::time::timeout(CALL_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "RPC call timed out"))??;
+
+    let results = parse_reply(&reply, xid)?;
+    Ok(results.data[results.offset..].to_vec())
+}
+
+// Reads fragments until the last one, and puts them back together
+async fn read_record(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut record = Vec::new();
+    loop {
+        let marker = stream.read_u32().await?;
+        let start = record.len();
+        let length = (marker & !LAST_FRAGMENT) as usize;
+        if start + length > MAX_REPLY_LENGTH {
+            return Err(invalid_data("RPC reply is too long"));
+        }
+        record.resize(start + length, 0);
+        stream.read_exact(&mut record[start..]).await?;
+        if marker & LAST_FRAGMENT != 0 {
+            return Ok(record);
+        }
+    }
+}
+
+async fn get_port(ip: IpAddr, rpcbind_port: u16, program: u32, version: u32) -> io::Result<u16> {
+    let mut args = Vec::with_capacity(16);
+    for value in [program, version, PROTOCOL_TCP, 0] {
+        args.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let results = call(
+        SocketAddr::new(ip, rpcbind_port),
+        PROGRAM_PORTMAP,
+        VERSION_PORTMAP,
+        PROCEDURE_GETPORT,
+        &args,
+    )
+    .await?;
+
+    match XdrReader::new(&results).read_u32()? {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("RPC program {} is not registered", program),
+        )),
+        port => Ok(port as u16),
+    }
+}
+
+// Makes sure both nfsd and mountd are registered with rpcbind, and returns the exported paths
+pub async fn list_exports(ip: IpAddr, rpcbind_port: u16) -> io::Result<Vec<String>> {
+    get_port(ip, rpcbind_port, PROGRAM_NFS, VERSION_NFS).await?;
+    let mount_port = get_port(ip, rpcbind_port, PROGRAM_MOUNT, VERSION_MOUNT).await?;
+
+    let results = call(
+        SocketAddr::new(ip, mount_port),
+        PROGRAM_MOUNT,
+        VERSION_MOUNT,
+        PROCEDURE_EXPORT,
+        &[],
+    )
+    .await?;
+    parse_export_list(&mut XdrReader::new(&results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn encode_string(value: &str, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(value.as_bytes());
+        buffer.resize(buffer.len().div_ceil(4) * 4, 0);
+    }
+
+    fn encode_export_list(exports: &[(&str, &[&str])]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for (path, groups) in exports {
+            buffer.extend_from_slice(&1u32.to_be_bytes());
+            encode_string(path, &mut buffer);
+            for group in *groups {
+                buffer.extend_from_slice(&1u32.to_be_bytes());
+                encode_string(group, &mut buffer);
+            }
+            buffer.extend_from_slice(&0u32.to_be_bytes());
+        }
+        buffer.extend_from_slice(&0u32.to_be_bytes());
+        buffer
+    }
+
+    // Answers rpcbind and mountd calls on the same port, with nfsd optionally unregistered
+    async fn spawn_rpc_responder(register_nfs: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let marker = stream.read_u32().await.unwrap();
+                let mut call = vec![0u8; (marker & !LAST_FRAGMENT) as usize];
+                stream.read_exact(&mut call).await.unwrap();
+
+                let mut reader = XdrReader::new(&call);
+                let fields: Vec<u32> = (0..10).map(|_| reader.read_u32().unwrap()).collect();
+                let (xid, program, procedure) = (fields[0], fields[3], fields[5]);
+
+                let results = match (program, procedure) {
+                    (PROGRAM_PORTMAP, PROCEDURE_GETPORT) => {
+                        let registered = match reader.read_u32().unwrap() {
+                            PROGRAM_NFS => register_nfs,
+                            PROGRAM_MOUNT => true,
+                            _ => false,
+                        };
+                        let port = if registered { address.port() as u32 } else { 0 };
+                        port.to_be_bytes().to_vec()
+                    }
+                    (PROGRAM_MOUNT, PROCEDURE_EXPORT) => encode_export_list(&[
+                        ("/srv/media", &["192.168.1.0/24"]),
+                        ("/srv/backup", &[]),
+                    ]),
+                    _ => panic!("unexpected RPC call"),
+                };
+
+                let mut reply = Vec::new();
+                for value in [xid, MESSAGE_REPLY, REPLY_ACCEPTED, 0, 0, ACCEPT_SUCCESS] {
+                    reply.extend_from_slice(&value.to_be_bytes());
+                }
+                reply.extend_from_slice(&results);
+
+                // Split the reply in two fragments to exercise reassembly
+                let (first, last) = reply.split_at(8);
+                let mut message = (first.len() as u32).to_be_bytes().to_vec();
+                message.extend_from_slice(first);
+                message.extend_from_slice(&(LAST_FRAGMENT | last.len() as u32).to_be_bytes());
+                message.extend_from_slice(last);
+                stream.write_all(&message).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn test_parse_export_list() {
+        let data = encode_export_list(&[("/srv/media", &["host1", "host2"]), ("/export", &[])]);
+        let exports = parse_export_list(&mut XdrReader::new(&data)).unwrap();
+        assert_eq!(exports, vec!["/srv/media", "/export"]);
+
+        assert!(parse_export_list(&mut XdrReader::new(&data[..10])).is_err());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let mut reply = Vec::new();
+        for value in [
+            42,
+            MESSAGE_REPLY,
+            REPLY_ACCEPTED,
+            0,
+            0,
+            ACCEPT_SUCCESS,
+            2049,
+        ] {
+            reply.extend_from_slice(&u32::to_be_bytes(value));
+        }
+        let mut results = parse_reply(&reply, 42).unwrap();
+        assert_eq!(results.read_u32().unwrap(), 2049);
+
+        assert!(parse_reply(&reply, 43).is_err());
+
+        // Program unavailable
+        reply[20..24].copy_from_slice(&1u32.to_be_bytes());
+        assert!(parse_reply(&reply, 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_record() {
+        let mut record: &[u8] = &[0, 0, 0, 2, b'a', b'b', 0x80, 0, 0, 1, b'c'];
+        assert_eq!(read_record(&mut record).await.unwrap(), b"abc");
+
+        // Rejected before anything is allocated for it
+        let mut record: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF];
+        let error = read_record(&mut record).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_call_timeout() {
+        // Accepts the connection, but never answers
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let error = list_exports(address.ip(), address.port())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_list_exports() {
+        let address = spawn_rpc_responder(true).await;
+        let exports = list_exports(address.ip(), address.port()).await.unwrap();
+        assert_eq!(exports, vec!["/srv/media", "/srv/backup"]);
+
+        let address = spawn_rpc_responder(false).await;
+        let result = list_exports(address.ip(), address.port()).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,222 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const SIZE_ICMP_HEADER: usize = 8;
+const PAYLOAD: &[u8] = b"rallyup";
+
+// Pings that run at the same time each get their own identifier
+static PINGS_STARTED: AtomicU16 = AtomicU16::new(0);
+
+// ICMP Echo Layout:
+// ----------------------------------------------------------------
+// | Type    | Code    | Checksum | Identifier | Sequence | Payload |
+// ----------------------------------------------------------------
+// | 1 byte  | 1 byte  | 2 bytes  | 2 bytes    | 2 bytes  | ...     |
+// ----------------------------------------------------------------
+// The checksum is only computed for ICMPv4, the kernel always fills it in for ICMPv6
+// since it needs the IPv6 pseudo-header.
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn create_echo_request(ip: IpAddr, identifier: u16, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; SIZE_ICMP_HEADER];
+    packet[0] = if ip.is_ipv4() {
+        ICMPV4_ECHO_REQUEST
+    } else {
+        ICMPV6_ECHO_REQUEST
+    };
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(PAYLOAD);
+
+    if ip.is_ipv4() {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+// Returns the sequence number if the packet is an echo reply meant for us
+fn parse_echo_reply(packet: &[u8], ip: IpAddr, identifier: Option<u16>) -> Option<u16> {
+    if packet.len() < SIZE_ICMP_HEADER {
+        return None;
+    }
+
+    let expected_type = if ip.is_ipv4() {
+        ICMPV4_ECHO_REPLY
+    } else {
+        ICMPV6_ECHO_REPLY
+    };
+    if packet[0] != expected_type {
+        return None;
+    }
+
+    if let Some(identifier) = identifier {
+        if u16::from_be_bytes([packet[4], packet[5]]) != identifier {
+            return None;
+        }
+    }
+
+    Some(u16::from_be_bytes([packet[6], packet[7]]))
+}
+
+// Raw sockets receive every echo reply that reaches the host, so the identifier is what tells
+// the replies of concurrent pings (from this process, or from others) apart
+fn next_identifier() -> u16 {
+    (std::process::id() as u16).wrapping_add(PINGS_STARTED.fetch_add(1, Ordering::Relaxed))
+}
+
+struct IcmpSocket {
+    socket: UdpSocket,
+    // Raw ICMPv4 sockets also receive the IP header
+    raw: bool,
+}
+
+fn open_socket(ip: IpAddr) -> std::io::Result<IcmpSocket> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+
+    // Datagram ICMP sockets do not need root, as long as the user is in
+    // net.ipv4.ping_group_range, otherwise fall back to a raw socket
+    let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(_) => (Socket::new(domain, Type::RAW, Some(protocol))?, true),
+    };
+
+    Ok(IcmpSocket {
+        socket: socket.into(),
+        raw,
+    })
+}
+
+fn ping_once(
+    socket: &IcmpSocket,
+    ip: IpAddr,
+    identifier: u16,
+    sequence: u16,
+    packet_timeout: Duration,
+) -> std::io::Result<bool> {
+    let request = create_echo_request(ip, identifier, sequence);
+    socket.socket.send_to(&request, SocketAddr::new(ip, 0))?;
+
+    let deadline = Instant::now() + packet_timeout;
+    let mut buffer = [0u8; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        socket.socket.set_read_timeout(Some(remaining))?;
+
+        let (received, source) = match socket.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if source.ip() != ip {
+            continue;
+        }
+
+        let mut packet = &buffer[..received];
+        if socket.raw && ip.is_ipv4() {
+            let header_length = usize::from(packet[0] & 0x0F) * 4;
+            packet = &packet[header_length.min(packet.len())..];
+        }
+
+        // The kernel picks the identifier for datagram sockets, and only hands us our own replies
+        let identifier = if socket.raw { Some(identifier) } else { None };
+        if parse_echo_reply(packet, ip, identifier) == Some(sequence) {
+            return Ok(true);
+        }
+    }
+}
+
+// Sends `count` echo requests one after the other, and passes if no more than
+// `max_loss` percent of them went unanswered
+pub fn ping(ip: IpAddr, count: u16, packet_timeout: Duration, max_loss: u8) -> bool {
+    let Ok(socket) = open_socket(ip) else {
+        return false;
+    };
+
+    let identifier = next_identifier();
+    let mut lost: u16 = 0;
+    for sequence in 0..count {
+        match ping_once(&socket, ip, identifier, sequence, packet_timeout) {
+            Ok(true) => {}
+            Ok(false) => lost += 1,
+            Err(_) => return false,
+        }
+    }
+
+    let loss = u32::from(lost) * 100 / u32::from(count.max(1));
+    loss <= u32::from(max_loss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // Echo request with identifier 1 and sequence 1, and no payload
+        let packet = [0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01];
+        assert_eq!(checksum(&packet), 0xF7FD);
+
+        // A packet with its checksum filled in sums up to zero
+        let packet = create_echo_request("127.0.0.1".parse().unwrap(), 0x1234, 7);
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut reply = create_echo_request(ip, 0x1234, 7);
+
+        // Requests are not replies
+        assert_eq!(parse_echo_reply(&reply, ip, Some(0x1234)), None);
+
+        reply[0] = ICMPV4_ECHO_REPLY;
+        assert_eq!(parse_echo_reply(&reply, ip, Some(0x1234)), Some(7));
+        assert_eq!(parse_echo_reply(&reply, ip, None), Some(7));
+        assert_eq!(parse_echo_reply(&reply, ip, Some(0x4321)), None);
+        assert_eq!(parse_echo_reply(&reply[..4], ip, None), None);
+    }
+
+    #[test]
+    fn test_next_identifier() {
+        assert_ne!(next_identifier(), next_identifier());
+    }
+
+    #[test]
+    #[ignore = "needs datagram ICMP sockets (net.ipv4.ping_group_range) or CAP_NET_RAW"]
+    fn test_ping_localhost() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(ping(ip, 2, Duration::from_secs(1), 0));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::process_group;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+const PLUGIN_PATH_VARIABLE: &str = "RALLYUP_PLUGIN_PATH";
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+// Messages are shown on a single line under the check
+const MAX_MESSAGE_LENGTH: usize = 80;
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("Plugin {0} was not found in the plugin path")]
+    NotFound(String),
+
+    #[error("Failed to run plugin {0}: {1}")]
+    RunError(String, std::io::Error),
+
+    #[error("Plugin {0} exited with {1}: {2}")]
+    PluginFailed(String, String, String),
+
+    #[error("Plugin {0} did not finish within {1:?}")]
+    TimedOut(String, Duration),
+
+    #[error("Plugin {0} returned invalid output: {1}")]
+    InvalidOutput(String, String),
+
+    #[error("Invalid parameters for plugin {0}: {1}")]
+    InvalidParams(String, String),
+}
+
+type Result<T> = std::result::Result<T, PluginError>;
+
+// What a plugin prints when run with `--describe`
+#[derive(Debug, Deserialize)]
+pub struct Description {
+    #[serde(default)]
+    pub params: HashMap<String, ParamSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ParamSpec {
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl ParamType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+            ParamType::Number => value.is_number(),
+            ParamType::Integer => value.is_i64() || value.is_u64(),
+            ParamType::Boolean => value.is_boolean(),
+            ParamType::Array => value.is_array(),
+            ParamType::Object => value.is_object(),
+        }
+    }
+}
+
+// What a plugin prints after running the check
+#[derive(Debug, Deserialize)]
+pub struct PluginResult {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub metrics: Map<String, Value>,
+}
+
+impl PluginResult {
+    // The message followed by the metrics, e.g. "3 VMs running (cpu=0.25, memory=0.6)"
+    pub fn summary(&self) -> Option<String> {
+        let metrics = self
+            .metrics
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let summary = match (&self.message, metrics.is_empty()) {
+            (Some(message), true) => message.clone(),
+            (Some(message), false) => format!("{} ({})", message, metrics),
+            (None, false) => metrics,
+            (None, true) => return None,
+        };
+        Some(single_line(&summary))
+    }
+}
+
+// The first line of the text, cut short if it is too long to show
+pub fn single_line(text: &str) -> String {
+    let line = text.trim().lines().next().unwrap_or_default();
+    if line.chars().count() <= MAX_MESSAGE_LENGTH {
+        return line.to_string();
+    }
+    let mut line: String = line.chars().take(MAX_MESSAGE_LENGTH - 3).collect();
+    line.push_str("...");
+    line
+}
+
+// Directories listed in RALLYUP_PLUGIN_PATH, separated like PATH, or the default locations
+fn search_path() -> Vec<PathBuf> {
+    if let Some(paths) = std::env::var_os(PLUGIN_PATH_VARIABLE) {
+        return std::env::split_paths(&paths).collect();
+    }
+
+    let mut paths = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".config/rallyup/plugins"));
+    }
+    paths.push(PathBuf::from("/usr/local/lib/rallyup/plugins"));
+    paths.push(PathBuf::from("/usr/lib/rallyup/plugins"));
+    paths
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Plugins given as a path are used as is, otherwise the first match in the search path wins
+fn find_plugin_in(name: &str, search_path: &[PathBuf]) -> Result<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) {
+            Ok(path)
+        } else {
+            Err(PluginError::NotFound(name.to_string()))
+        };
+    }
+
+    search_path
+        .iter()
+        .map(|directory| directory.join(name))
+        .find(|path| is_executable(path))
+        .ok_or_else(|| PluginError::NotFound(name.to_string()))
+}
+
+fn find_plugin(name: &str) -> Result<PathBuf> {
+    find_plugin_in(name, &search_path())
+}
+
+// Reads the pipe to the end on its own thread, so that a plugin that fills it up is not stuck
+fn read_pipe(mut pipe: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = pipe.read_to_end(&mut output);
+        output
+    })
+}
+
+// Configs are validated before anything is started, so this runs synchronously
+fn describe_within(name: &str, path: &Path, timeout: Duration) -> Result<Description> {
+    let run_error = |e| PluginError::RunError(name.to_string(), e);
+
+    let mut child = std::process::Command::new(path)
+        .arg("--describe")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(run_error)?;
+    let stdout = read_pipe(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_pipe(child.stderr.take().expect("stderr is piped"));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(run_error)? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            process_group::kill(child.id());
+            let _ = child.wait();
+            return Err(PluginError::TimedOut(name.to_string(), timeout));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        return Err(PluginError::PluginFailed(
+            name.to_string(),
+            status.to_string(),
+            single_line(&String::from_utf8_lossy(&stderr)),
+        ));
+    }
+
+    serde_json::from_slice(&stdout)
+        .map_err(|e| PluginError::InvalidOutput(name.to_string(), e.to_string()))
+}
+
+fn describe(name: &str, path: &Path) -> Result<Description> {
+    describe_within(name, path, DESCRIBE_TIMEOUT)
+}
+
+fn validate_params(
+    name: &str,
+    description: &Description,
+    params: &Map<String, Value>,
+) -> Result<()> {
+    let invalid = |message: String| Err(PluginError::InvalidParams(name.to_string(), message));
+
+    for (param, spec) in &description.params {
+        match params.get(param) {
+            Some(value) if !spec.kind.matches(value) => {
+                return invalid(format!(
+                    "{} should be of type {}",
+                    param,
+                    format!("{:?}", spec.kind).to_lowercase()
+                ));
+            }
+            None if spec.required => {
+                return invalid(format!("{} is required", param));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(param) = params
+        .keys()
+        .find(|param| !description.params.contains_key(*param))
+    {
+        return invalid(format!("unknown parameter {}", param));
+    }
+    Ok(())
+}
+
+// Makes sure the plugin can be found and accepts the parameters, according to its description
+pub fn validate_plugin(name: &str, params: &Map<String, Value>) -> Result<()> {
+    let path = find_plugin(name)?;
+    let description = describe(name, &path)?;
+    validate_params(name, &description, params)
+}
+
+async fn run_plugin_at(
+    name: &str,
+    path: &Path,
+    params: &Map<String, Value>,
+) -> Result<PluginResult> {
+    let run_error = |e| PluginError::RunError(name.to_string(), e);
+
+    // Kill the plugin, and whatever it started, if the attempt is cancelled because it took
+    // too long
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(run_error)?;
+    let guard = process_group::Guard::new(child.id());
+
+    // Parameters are sent as a JSON object on stdin, closing it lets the plugin know that's all
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = serde_json::to_vec(params).expect("JSON values always serialize");
+    if let Err(e) = stdin.write_all(&input).await {
+        // Plugins that don't take any parameters may exit without reading them
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(run_error(e));
+        }
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().await.map_err(run_error)?;
+    guard.finish();
+
+    match serde_json::from_slice(&output.stdout) {
+        Ok(result) => Ok(result),
+        Err(_) if !output.status.success() => Err(PluginError::PluginFailed(
+            name.to_string(),
+            output.status.to_string(),
+            single_line(&String::from_utf8_lossy(&output.stderr)),
+        )),
+        Err(e) => Err(PluginError::InvalidOutput(name.to_string(), e.to_string())),
+    }
+}
+
+pub async fn run_plugin(name: &str, params: &Map<String, Value>) -> Result<PluginResult> {
+    let path = find_plugin(name)?;
+    run_plugin_at(name, &path, params).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEST_PLUGIN: &str = r#"#!/bin/sh
+if [ "$1" = "--describe" ]; then
+    echo '{"params": {"target": {"type": "string", "required": true}, "count": {"type": "integer"}}}'
+    exit 0
+fi
+input=$(cat)
+case "$input" in
+    *'"target":"up"'*) echo '{"ok": true, "message": "all good", "metrics": {"latency_ms": 12}}' ;;
+    *'"target":"down"'*) echo '{"ok": false, "message": "target is down"}' ;;
+    *) echo "no idea what to do" >&2; exit 2 ;;
+esac
+"#;
+
+    fn install_plugin(name: &str) -> PathBuf {
+        install_script(name, TEST_PLUGIN)
+    }
+
+    fn install_script(name: &str, script: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("rallyup-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        directory
+    }
+
+    fn params(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_find_and_describe_plugin() {
+        let directory = install_plugin("describe-test");
+        let search_path = vec![PathBuf::from("/nonexistent"), directory.clone()];
+
+        let path = find_plugin_in("describe-test", &search_path).unwrap();
+        assert_eq!(path, directory.join("describe-test"));
+        assert!(find_plugin_in(path.to_str().unwrap(), &[]).is_ok());
+        assert!(matches!(
+            find_plugin_in("missing", &search_path),
+            Err(PluginError::NotFound(_))
+        ));
+
+        let description = describe("describe-test", &path).unwrap();
+        assert!(validate_params("test", &description, &params(json!({"target": "up"}))).is_ok());
+        assert!(validate_params(
+            "test",
+            &description,
+            &params(json!({"target": "up", "count": 3}))
+        )
+        .is_ok());
+
+        for invalid in [
+            json!({}),
+            json!({"target": 1}),
+            json!({"target": "up", "count": 1.5}),
+            json!({"target": "up", "unknown": true}),
+        ] {
+            assert!(matches!(
+                validate_params("test", &description, &params(invalid)),
+                Err(PluginError::InvalidParams(_, _))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_plugin() {
+        let directory = install_plugin("run-test");
+        let path = directory.join("run-test");
+
+        let result = run_plugin_at("test", &path, &params(json!({"target": "up"})))
+            .await
+            .unwrap();
+        assert!(result.ok);
+        assert_eq!(result.summary().unwrap(), "all good (latency_ms=12)");
+
+        let result = run_plugin_at("test", &path, &params(json!({"target": "down"})))
+            .await
+            .unwrap();
+        assert!(!result.ok);
+        assert_eq!(result.summary().unwrap(), "target is down");
+
+        let result = run_plugin_at("test", &path, &params(json!({"target": "sideways"}))).await;
+        assert!(matches!(
+            result,
+            Err(PluginError::PluginFailed(_, _, stderr)) if stderr == "no idea what to do"
+        ));
+    }
+
+    #[test]
+    fn test_describe_timeout() {
+        let directory = install_script("hang-test", "#!/bin/sh\nexec sleep 30\n");
+        let path = directory.join("hang-test");
+
+        let start_time = Instant::now();
+        assert!(matches!(
+            describe_within("hang-test", &path, Duration::from_millis(200)),
+            Err(PluginError::TimedOut(_, _))
+        ));
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_summary_single_line() {
+        let result = PluginResult {
+            ok: false,
+            message: Some("first line\nsecond line".into()),
+            metrics: Map::new(),
+        };
+        assert_eq!(result.summary().unwrap(), "first line");
+
+        let result = PluginResult {
+            ok: false,
+            message: Some("x".repeat(200)),
+            metrics: Map::new(),
+        };
+        let summary = result.summary().unwrap();
+        assert_eq!(summary.len(), MAX_MESSAGE_LENGTH);
+        assert!(summary.ends_with("..."));
+    }
+}
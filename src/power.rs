@@ -0,0 +1,688 @@
+use crate::http::{self, ClientOptions, HttpError, HttpRequest};
+use crate::ipmi::{self, IpmiError, Privilege};
+use crate::plug::{self, PlugError, PlugKind};
+use crate::process_group;
+use crate::proxmox::{self, Guest, ProxmoxError};
+use crate::redfish::{self, PowerOnOutcome, RedfishError};
+use crate::snmp::{self, SnmpError, SnmpVersion};
+use crate::wol::{self, WOLError};
+use colored::Colorize;
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use tokio::process::Command;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PowerError {
+    #[error(transparent)]
+    WakeError(#[from] WOLError),
+
+    #[error("Failed to run power-on command: {0}")]
+    CommandError(#[from] std::io::Error),
+
+    #[error("Power-on command exited with {0}: {1}")]
+    CommandFailed(String, String),
+
+    #[error(transparent)]
+    HttpError(#[from] HttpError),
+
+    #[error("Power-on request returned unexpected status: {0}")]
+    UnexpectedStatus(u16),
+
+    #[error("Failed to read secret: {0}")]
+    SecretError(String),
+
+    #[error(transparent)]
+    RedfishError(#[from] RedfishError),
+
+    #[error(transparent)]
+    IpmiError(#[from] IpmiError),
+
+    #[error(transparent)]
+    ProxmoxError(#[from] ProxmoxError),
+
+    #[error(transparent)]
+    SnmpError(#[from] SnmpError),
+
+    #[error("Invalid OID: {0}")]
+    InvalidOid(String),
+
+    #[error(transparent)]
+    PlugError(#[from] PlugError),
+
+    #[error("Power-on did not finish within {0:?}")]
+    TimedOut(Duration),
+}
+
+type Result<T> = std::result::Result<T, PowerError>;
+
+// How long a power-on may take, for methods that don't wait on anything of their own
+const POWER_ON_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn default_wol_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::BROADCAST)
+}
+
+fn default_wol_port() -> u16 {
+    9
+}
+
+fn default_http_method() -> String {
+    "POST".into()
+}
+
+fn default_ipmi_port() -> u16 {
+    623
+}
+
+fn default_task_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_snmp_port() -> u16 {
+    161
+}
+
+fn default_cipher_suite() -> u8 {
+    // RAKP-HMAC-SHA1, HMAC-SHA1-96 and AES-CBC-128, which every IPMI v2.0 BMC supports
+    3
+}
+
+// Passwords and tokens are read from a file or an environment variable, so that they don't
+// have to be written in the config, e.g. `password: { env: BMC_PASSWORD }`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    File { file: String },
+    Env { env: String },
+}
+
+impl Secret {
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Secret::File { file: path } => fs::read_to_string(path)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| PowerError::SecretError(format!("{}: {}", path, e))),
+            Secret::Env { env: name } => std::env::var(name)
+                .map_err(|_| PowerError::SecretError(format!("{} is not set", name))),
+        }
+    }
+}
+
+// Turns the outlet off for `cycle` first, if set, for devices that hang and only recover when
+// their power comes back
+async fn switch_on<F>(cycle: Option<Duration>, switch: impl Fn(bool) -> F) -> Result<bool>
+where
+    F: Future<Output = Result<()>>,
+{
+    if let Some(off_time) = cycle {
+        switch(false).await?;
+        tokio::time::sleep(off_time).await;
+    }
+    switch(true).await?;
+    Ok(true)
+}
+
+// Every way of turning a server on. Implementations only ask for the server to be turned on,
+// the health checks are what tell whether it actually came up.
+pub trait PowerOnBackend {
+    // Returns false when the server was found already on, so nothing was sent
+    async fn power_on(&self) -> Result<bool>;
+
+    // Whether asking again while the server boots is harmless, so it can be resent
+    fn can_resend(&self) -> bool {
+        false
+    }
+
+    // How long a single power-on may take before it is given up on
+    fn timeout(&self) -> Duration {
+        POWER_ON_TIMEOUT
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WolTransport {
+    // Raw EtherType 0x0842 frame sent out of the server's interface
+    #[default]
+    Ethernet,
+    // Magic packet in a UDP datagram, sent to a broadcast or unicast address
+    Udp {
+        #[serde(default = "default_wol_address")]
+        address: IpAddr,
+        #[serde(default = "default_wol_port")]
+        port: u16,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WolPowerOn {
+    pub mac: String,
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    #[serde(default)]
+    pub transport: WolTransport,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl PowerOnBackend for WolPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        match &self.transport {
+            WolTransport::Ethernet => {
+                // The interface is required for this transport when the config is validated
+                let interface = self.interface.as_deref().unwrap_or_default();
+                wol::send_wol_packet(&self.mac, interface, self.vlan, self.password.as_deref())?;
+            }
+            WolTransport::Udp { address, port } => {
+                wol::send_wol_udp_packet(&self.mac, *address, *port, self.password.as_deref())?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn can_resend(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShellPowerOn {
+    pub command: String,
+}
+
+impl PowerOnBackend for ShellPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        let output = process_group::output(Command::new("sh").arg("-c").arg(&self.command)).await?;
+
+        if !output.status.success() {
+            return Err(PowerError::CommandFailed(
+                output.status.to_string(),
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpPowerOn {
+    pub url: String,
+    // `method` already picks the power-on method
+    #[serde(default = "default_http_method")]
+    pub http_method: String,
+    // Any 2xx status when not set
+    pub status: Option<u16>,
+    #[serde(flatten)]
+    pub request: HttpRequest,
+}
+
+impl HttpPowerOn {
+    pub fn request(&self) -> HttpRequest {
+        HttpRequest {
+            method: self.http_method.clone(),
+            ..self.request.clone()
+        }
+    }
+}
+
+impl PowerOnBackend for HttpPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        let status = http::send(&self.url, &self.request()).await?.status();
+        let ok = match self.status {
+            Some(expected) => status.as_u16() == expected,
+            None => status.is_success(),
+        };
+        if !ok {
+            return Err(PowerError::UnexpectedStatus(status.as_u16()));
+        }
+        Ok(true)
+    }
+
+    // The request has its own timeout, which can be set longer than the default
+    fn timeout(&self) -> Duration {
+        self.request.client.request_timeout.max(POWER_ON_TIMEOUT)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedfishPowerOn {
+    // Base URL of the BMC, e.g. https://192.168.1.20
+    pub url: String,
+    pub username: String,
+    pub password: Secret,
+    // Id of the ComputerSystem, only needed when the BMC manages more than one
+    pub system: Option<String>,
+    #[serde(flatten)]
+    pub client: ClientOptions,
+}
+
+impl PowerOnBackend for RedfishPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        let password = self.password.read()?;
+        let login = redfish::Login {
+            username: &self.username,
+            password: &password,
+        };
+        let outcome =
+            redfish::power_on(&self.url, &self.client, &login, self.system.as_deref()).await?;
+        Ok(outcome == PowerOnOutcome::Started)
+    }
+
+    // Systems that are already on are left alone
+    fn can_resend(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IpmiPowerOn {
+    pub address: String,
+    #[serde(default = "default_ipmi_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: Secret,
+    #[serde(default = "default_cipher_suite")]
+    pub cipher_suite: u8,
+    #[serde(default)]
+    pub privilege: Privilege,
+}
+
+impl PowerOnBackend for IpmiPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        let password = self.password.read()?;
+        let login = ipmi::Login {
+            username: &self.username,
+            password: &password,
+            privilege: self.privilege,
+        };
+        Ok(ipmi::power_on(&self.address, self.port, &login, self.cipher_suite).await?)
+    }
+
+    // Chassis that are already on are left alone
+    fn can_resend(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxmoxPowerOn {
+    // e.g. https://pve1.lan:8006
+    pub url: String,
+    // The whole API token, `USER@REALM!TOKENID=SECRET`
+    pub token: Secret,
+    pub node: String,
+    pub vmid: u32,
+    #[serde(default)]
+    pub guest: Guest,
+    // How long to wait for the start task to finish
+    #[serde(default = "default_task_timeout", with = "humantime_serde")]
+    pub task_timeout: Duration,
+    #[serde(flatten)]
+    pub client: ClientOptions,
+}
+
+impl PowerOnBackend for ProxmoxPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        let token = self.token.read()?;
+        let api = proxmox::Api {
+            url: &self.url,
+            token: &token,
+            client: &self.client,
+        };
+        Ok(
+            proxmox::start_guest(&api, &self.node, self.guest, self.vmid, self.task_timeout)
+                .await?,
+        )
+    }
+
+    // Guests that are already running are left alone
+    fn can_resend(&self) -> bool {
+        true
+    }
+
+    // The start task can take up to task_timeout on top of the API calls
+    fn timeout(&self) -> Duration {
+        self.task_timeout + POWER_ON_TIMEOUT
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SnmpPowerOn {
+    pub address: String,
+    #[serde(default = "default_snmp_port")]
+    pub port: u16,
+    // Needs write access, which is usually the `private` community
+    pub community: Secret,
+    #[serde(default)]
+    pub version: SnmpVersion,
+    // Control object of the outlet, e.g. sPDUOutletCtl.5 on APC PDUs
+    pub oid: String,
+    pub on_value: i64,
+    // Only needed to power cycle the outlet
+    pub off_value: Option<i64>,
+    // How long the outlet stays off in a power cycle, no power cycle when not set
+    #[serde(default, with = "humantime_serde")]
+    pub cycle: Option<Duration>,
+}
+
+impl SnmpPowerOn {
+    async fn switch(&self, on: bool) -> Result<()> {
+        let community = self.community.read()?;
+        let agent = snmp::Agent {
+            host: &self.address,
+            port: self.port,
+            community: &community,
+            version: self.version,
+        };
+        let oid =
+            snmp::parse_oid(&self.oid).ok_or_else(|| PowerError::InvalidOid(self.oid.clone()))?;
+        // Validation makes sure it is set when the outlet is power cycled
+        let value = if on {
+            self.on_value
+        } else {
+            self.off_value.unwrap_or_default()
+        };
+        snmp::set_integer(&agent, &oid, value).await?;
+        Ok(())
+    }
+}
+
+impl PowerOnBackend for SnmpPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        switch_on(self.cycle, |on| self.switch(on)).await
+    }
+
+    // Turning an outlet on again is harmless, power cycling it again isn't
+    fn can_resend(&self) -> bool {
+        self.cycle.is_none()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.cycle.unwrap_or_default() + POWER_ON_TIMEOUT
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PlugPowerOn {
+    // e.g. http://192.168.1.50
+    pub url: String,
+    pub kind: PlugKind,
+    // The first relay when not set
+    pub relay: Option<u8>,
+    // `admin` when a password is set
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    // How long the relay stays off in a power cycle, no power cycle when not set
+    #[serde(default, with = "humantime_serde")]
+    pub cycle: Option<Duration>,
+    #[serde(flatten)]
+    pub client: ClientOptions,
+}
+
+impl PlugPowerOn {
+    async fn switch(&self, on: bool) -> Result<()> {
+        let password = self.password.as_ref().map(Secret::read).transpose()?;
+        let plug = plug::Plug {
+            url: &self.url,
+            kind: self.kind,
+            relay: self.relay.unwrap_or(self.kind.default_relay()),
+            username: self.username.as_deref(),
+            password: password.as_deref(),
+            client: &self.client,
+        };
+        plug.switch(on).await?;
+        Ok(())
+    }
+}
+
+impl PowerOnBackend for PlugPowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        switch_on(self.cycle, |on| self.switch(on)).await
+    }
+
+    // Turning a relay on again is harmless, power cycling it again isn't
+    fn can_resend(&self) -> bool {
+        self.cycle.is_none()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.cycle.unwrap_or_default() + POWER_ON_TIMEOUT
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum PowerOn {
+    Wol(WolPowerOn),
+    Shell(ShellPowerOn),
+    Http(HttpPowerOn),
+    Redfish(RedfishPowerOn),
+    Ipmi(IpmiPowerOn),
+    Proxmox(ProxmoxPowerOn),
+    Snmp(SnmpPowerOn),
+    Plug(PlugPowerOn),
+}
+
+impl PowerOnBackend for PowerOn {
+    async fn power_on(&self) -> Result<bool> {
+        match self {
+            PowerOn::Wol(wol) => wol.power_on().await,
+            PowerOn::Shell(shell) => shell.power_on().await,
+            PowerOn::Http(http) => http.power_on().await,
+            PowerOn::Redfish(redfish) => redfish.power_on().await,
+            PowerOn::Ipmi(ipmi) => ipmi.power_on().await,
+            PowerOn::Proxmox(proxmox) => proxmox.power_on().await,
+            PowerOn::Snmp(snmp) => snmp.power_on().await,
+            PowerOn::Plug(plug) => plug.power_on().await,
+        }
+    }
+
+    fn can_resend(&self) -> bool {
+        match self {
+            PowerOn::Wol(wol) => wol.can_resend(),
+            PowerOn::Shell(shell) => shell.can_resend(),
+            PowerOn::Http(http) => http.can_resend(),
+            PowerOn::Redfish(redfish) => redfish.can_resend(),
+            PowerOn::Ipmi(ipmi) => ipmi.can_resend(),
+            PowerOn::Proxmox(proxmox) => proxmox.can_resend(),
+            PowerOn::Snmp(snmp) => snmp.can_resend(),
+            PowerOn::Plug(plug) => plug.can_resend(),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        match self {
+            PowerOn::Wol(wol) => wol.timeout(),
+            PowerOn::Shell(shell) => shell.timeout(),
+            PowerOn::Http(http) => http.timeout(),
+            PowerOn::Redfish(redfish) => redfish.timeout(),
+            PowerOn::Ipmi(ipmi) => ipmi.timeout(),
+            PowerOn::Proxmox(proxmox) => proxmox.timeout(),
+            PowerOn::Snmp(snmp) => snmp.timeout(),
+            PowerOn::Plug(plug) => plug.timeout(),
+        }
+    }
+}
+
+impl fmt::Display for PowerOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerOn::Wol(wol) => write!(f, "{} [{}]", "wol".bold(), wol.mac),
+            PowerOn::Shell(_) => write!(f, "{}", "shell".bold()),
+            PowerOn::Http(http) => write!(f, "{} [{}]", "http".bold(), http.url),
+            PowerOn::Redfish(redfish) => write!(f, "{} [{}]", "redfish".bold(), redfish.url),
+            PowerOn::Ipmi(ipmi) => write!(f, "{} [{}:{}]", "ipmi".bold(), ipmi.address, ipmi.port),
+            PowerOn::Proxmox(proxmox) => {
+                write!(
+                    f,
+                    "{} [{}/{}]",
+                    "proxmox".bold(),
+                    proxmox.node,
+                    proxmox.vmid
+                )
+            }
+            PowerOn::Snmp(snmp) => write!(f, "{} [{}:{}]", "snmp".bold(), snmp.address, snmp.port),
+            PowerOn::Plug(plug) => write!(f, "{} [{}]", "plug".bold(), plug.url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn power_on(yaml: &str) -> PowerOn {
+        serde_yaml_ng::from_str(yaml).expect("Failed to parse YAML")
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let path = std::env::temp_dir().join(format!("rallyup-secret-{}", std::process::id()));
+        fs::write(&path, "calvin\n").unwrap();
+        let secret = Secret::File {
+            file: path.to_string_lossy().into(),
+        };
+        assert_eq!(secret.read().unwrap(), "calvin");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(secret.read(), Err(PowerError::SecretError(_))));
+
+        let secret: Secret = serde_yaml_ng::from_str("{env: RALLYUP_TEST_UNSET_SECRET}").unwrap();
+        assert!(matches!(secret.read(), Err(PowerError::SecretError(_))));
+    }
+
+    #[test]
+    fn test_parse_proxmox() {
+        let method = power_on(
+            r#"
+            method: proxmox
+            url: "https://pve1.lan:8006"
+            token:
+              file: /etc/rallyup/pve.token
+            node: pve1
+            vmid: 200
+            guest: lxc
+            task_timeout: 2m
+            insecure_skip_verify: true
+            "#,
+        );
+        let PowerOn::Proxmox(proxmox) = method else {
+            panic!("expected the proxmox method");
+        };
+        assert_eq!(proxmox.guest, Guest::Lxc);
+        assert_eq!(proxmox.task_timeout, std::time::Duration::from_secs(120));
+        assert_eq!(proxmox.timeout(), std::time::Duration::from_secs(180));
+        assert!(proxmox.client.insecure_skip_verify);
+        assert!(proxmox.can_resend());
+    }
+
+    #[tokio::test]
+    async fn test_shell_power_on() {
+        assert!(power_on("{method: shell, command: 'true'}")
+            .power_on()
+            .await
+            .is_ok());
+        assert!(matches!(
+            power_on("{method: shell, command: 'echo busy >&2; exit 2'}").power_on().await,
+            Err(PowerError::CommandFailed(_, stderr)) if stderr == "busy"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_power_on() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/outlet/3/on")
+            .match_header("authorization", "Bearer token")
+            .with_status(202)
+            .create_async()
+            .await;
+
+        let method = power_on(&format!(
+            "{{method: http, url: '{}/outlet/3/on', auth: {{type: bearer, token: token}}}}",
+            server.url()
+        ));
+        assert!(matches!(method.power_on().await, Ok(true)));
+        mock.assert_async().await;
+
+        let method = power_on(&format!(
+            "{{method: http, url: '{}/outlet/3/on', http_method: get, status: 200}}",
+            server.url()
+        ));
+        assert!(matches!(
+            method.power_on().await,
+            Err(PowerError::UnexpectedStatus(501))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plug_power_cycle() {
+        let mut server = mockito::Server::new_async().await;
+        let off = server
+            .mock("GET", "/relay/1")
+            .match_query(mockito::Matcher::UrlEncoded("turn".into(), "off".into()))
+            .with_body(r#"{"ison": false}"#)
+            .create_async()
+            .await;
+        let on = server
+            .mock("GET", "/relay/1")
+            .match_query(mockito::Matcher::UrlEncoded("turn".into(), "on".into()))
+            .with_body(r#"{"ison": true}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let method = power_on(&format!(
+            "{{method: plug, url: '{}', kind: shelly, relay: 1}}",
+            server.url()
+        ));
+        assert!(method.can_resend());
+        assert!(matches!(method.power_on().await, Ok(true)));
+        assert!(!off.matched_async().await);
+
+        let method = power_on(&format!(
+            "{{method: plug, url: '{}', kind: shelly, relay: 1, cycle: 10ms}}",
+            server.url()
+        ));
+        assert!(!method.can_resend());
+        assert!(matches!(method.power_on().await, Ok(true)));
+        off.assert_async().await;
+        on.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_redfish_already_on() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/redfish/v1/SessionService/Sessions")
+            .with_status(201)
+            .with_header("X-Auth-Token", "token")
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/redfish/v1/Systems")
+            .with_body(r#"{"Members": [{"@odata.id": "/redfish/v1/Systems/1"}]}"#)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/redfish/v1/Systems/1")
+            .with_body(r#"{"PowerState": "On"}"#)
+            .create_async()
+            .await;
+
+        std::env::set_var("RALLYUP_TEST_REDFISH_PASSWORD", "calvin");
+        let method = power_on(&format!(
+            "{{method: redfish, url: '{}', username: root, password: {{env: RALLYUP_TEST_REDFISH_PASSWORD}}}}",
+            server.url()
+        ));
+        assert!(matches!(method.power_on().await, Ok(false)));
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::process::{Output, Stdio};
+use tokio::process::Command;
+
+// Commands are started in their own process group, so that everything they start can be killed
+// along with them. Killing only the direct child would leave the rest of a pipeline, or the other
+// commands of an `sh -c` script, running after the attempt gave up on them.
+pub fn kill(pid: u32) {
+    // SAFETY: killpg only sends a signal, a group that is already gone just makes it fail
+    unsafe {
+        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+// Kills the process group when dropped before `finish`, e.g. when an attempt that timed out is
+// cancelled while waiting on the command
+pub struct Guard(Option<u32>);
+
+impl Guard {
+    pub fn new(pid: Option<u32>) -> Self {
+        Guard(pid)
+    }
+
+    pub fn finish(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            kill(pid);
+        }
+    }
+}
+
+// Like `Command::output`, with the command in its own process group
+pub async fn output(command: &mut Command) -> io::Result<Output> {
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?;
+    let guard = Guard::new(child.id());
+    let output = child.wait_with_output().await;
+    guard.finish();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn is_running(pid: u32) -> bool {
+        // Killed processes can linger as zombies until they are reaped
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| !stat.contains(") Z "))
+    }
+
+    #[tokio::test]
+    async fn test_output_kills_group() {
+        let hello = output(Command::new("sh").arg("-c").arg("echo hello | cat"))
+            .await
+            .unwrap();
+        assert_eq!(hello.stdout, b"hello\n");
+
+        let marker = std::env::temp_dir().join(format!("rallyup-group-{}", std::process::id()));
+        let script = format!("sleep 600 & echo $! > {}; wait", marker.display());
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&script);
+        let attempt = tokio::time::timeout(Duration::from_millis(500), output(&mut command)).await;
+        assert!(attempt.is_err());
+
+        let pid: u32 = std::fs::read_to_string(&marker)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        std::fs::remove_file(&marker).unwrap();
+        for _ in 0..100 {
+            if !is_running(pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("sleep {} outlived the cancelled command", pid);
+    }
+}
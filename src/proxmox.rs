@@ -0,0 +1,342 @@
+use crate::http::{self, ClientOptions, HttpError, HttpRequest};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum ProxmoxError {
+    #[error(transparent)]
+    RequestError(#[from] HttpError),
+
+    #[error("Proxmox rejected the API token")]
+    AuthenticationFailed,
+
+    #[error("Proxmox returned {1} for {0}")]
+    UnexpectedStatus(String, u16),
+
+    #[error("Invalid response from Proxmox for {0}")]
+    InvalidResponse(String),
+
+    #[error("Start task failed: {0}")]
+    TaskFailed(String),
+
+    #[error("Start task did not finish in time: {0}")]
+    TaskTimedOut(String),
+
+    #[error("Guest is {0}, which can't be started from here")]
+    UnexpectedState(String),
+}
+
+type Result<T> = std::result::Result<T, ProxmoxError>;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Guest {
+    // Virtual machine
+    #[default]
+    Qemu,
+    // Container
+    Lxc,
+}
+
+impl Guest {
+    fn path(self) -> &'static str {
+        match self {
+            Guest::Qemu => "qemu",
+            Guest::Lxc => "lxc",
+        }
+    }
+}
+
+pub struct Api<'a> {
+    // e.g. https://pve1.lan:8006
+    pub url: &'a str,
+    // The whole API token, `USER@REALM!TOKENID=SECRET`
+    pub token: &'a str,
+    pub client: &'a ClientOptions,
+}
+
+impl Api<'_> {
+    async fn send(&self, method: &str, path: &str) -> Result<Value> {
+        let request = HttpRequest {
+            method: method.into(),
+            headers: HashMap::from([(
+                "Authorization".to_string(),
+                format!("PVEAPIToken={}", self.token),
+            )]),
+            client: self.client.clone(),
+            ..Default::default()
+        };
+        let url = format!("{}/api2/json{}", self.url.trim_end_matches('/'), path);
+        let response = http::send(&url, &request).await?;
+
+        match response.status().as_u16() {
+            401 => return Err(ProxmoxError::AuthenticationFailed),
+            status if !(200..300).contains(&status) => {
+                return Err(ProxmoxError::UnexpectedStatus(path.into(), status))
+            }
+            _ => {}
+        }
+        // Everything the API returns is wrapped in `data`
+        let body = response.text().await.map_err(HttpError::from)?;
+        let mut body: Value =
+            serde_json::from_str(&body).map_err(|_| ProxmoxError::InvalidResponse(path.into()))?;
+        Ok(body["data"].take())
+    }
+
+    // Waits for the task to stop, which is when its exit status is known
+    async fn wait_for_task(&self, node: &str, upid: &str) -> Result<()> {
+        let path = format!("/nodes/{}/tasks/{}/status", node, upid);
+        loop {
+            let task = self.send("GET", &path).await?;
+            if task["status"].as_str() == Some("stopped") {
+                return match task["exitstatus"].as_str() {
+                    Some("OK") => Ok(()),
+                    Some(status) => Err(ProxmoxError::TaskFailed(status.into())),
+                    None => Err(ProxmoxError::InvalidResponse(path)),
+                };
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+// Starts the guest if it is stopped, or resumes it if it is paused, and waits for that task to
+// finish. Returns whether it had to be started.
+pub async fn start_guest(
+    api: &Api<'_>,
+    node: &str,
+    guest: Guest,
+    vmid: u32,
+    task_timeout: Duration,
+) -> Result<bool> {
+    let path = format!("/nodes/{}/{}/{}/status", node, guest.path(), vmid);
+
+    let current = api.send("GET", &format!("{}/current", path)).await?;
+    let status = current["status"]
+        .as_str()
+        .ok_or_else(|| ProxmoxError::InvalidResponse(format!("{}/current", path)))?;
+    // Virtual machines stay `running` while paused or suspended, `qmpstatus` tells them apart
+    let action = match (status, current["qmpstatus"].as_str()) {
+        ("running", None | Some("running")) => return Ok(false),
+        ("stopped", _) => "start",
+        ("running", Some("paused" | "suspended")) => "resume",
+        ("running", Some(state)) | (state, _) => {
+            return Err(ProxmoxError::UnexpectedState(state.into()))
+        }
+    };
+
+    let upid = api.send("POST", &format!("{}/{}", path, action)).await?;
+    let upid = upid
+        .as_str()
+        .ok_or_else(|| ProxmoxError::InvalidResponse(format!("{}/{}", path, action)))?;
+    tokio::time::timeout(task_timeout, api.wait_for_task(node, upid))
+        .await
+        .map_err(|_| ProxmoxError::TaskTimedOut(upid.into()))??;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, ServerGuard};
+
+    const TOKEN: &str = "root@pam!rallyup=0b8e4c2a-5d1f-4a7e-9c3b-2f6d8e1a4b7c";
+    const UPID: &str = "UPID:pve1:000C2E4A:0A3B9F2D:66F1A2B3:qmstart:101:root@pam!rallyup:";
+
+    async fn start(url: &str, token: &str, guest: Guest, vmid: u32, timeout: u64) -> Result<bool> {
+        let api = Api {
+            url,
+            token,
+            client: &ClientOptions::default(),
+        };
+        start_guest(&api, "pve1", guest, vmid, Duration::from_millis(timeout)).await
+    }
+
+    async fn mock_status(server: &mut ServerGuard, path: &str, status: &str) {
+        server
+            .mock("GET", path)
+            .match_header("authorization", format!("PVEAPIToken={}", TOKEN).as_str())
+            .with_body(format!(
+                r#"{{"data": {{"status": "{}", "vmid": 101}}}}"#,
+                status
+            ))
+            .create_async()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_start_guest() {
+        let mut server = mockito::Server::new_async().await;
+        mock_status(
+            &mut server,
+            "/api2/json/nodes/pve1/qemu/101/status/current",
+            "stopped",
+        )
+        .await;
+        let start_request = server
+            .mock("POST", "/api2/json/nodes/pve1/qemu/101/status/start")
+            .match_header("authorization", format!("PVEAPIToken={}", TOKEN).as_str())
+            .with_body(format!(r#"{{"data": "{}"}}"#, UPID))
+            .create_async()
+            .await;
+        server
+            .mock(
+                "GET",
+                format!("/api2/json/nodes/pve1/tasks/{}/status", UPID).as_str(),
+            )
+            .with_body(r#"{"data": {"status": "stopped", "exitstatus": "OK"}}"#)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Qemu, 101, 5000).await;
+        assert!(started.unwrap());
+        start_request.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_start_running_guest() {
+        let mut server = mockito::Server::new_async().await;
+        mock_status(
+            &mut server,
+            "/api2/json/nodes/pve1/lxc/200/status/current",
+            "running",
+        )
+        .await;
+        let start_request = server
+            .mock("POST", Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Lxc, 200, 5000).await;
+        assert!(!started.unwrap());
+        start_request.assert_async().await;
+    }
+
+    async fn mock_qmp_status(server: &mut ServerGuard, qmp_status: &str) {
+        server
+            .mock("GET", "/api2/json/nodes/pve1/qemu/101/status/current")
+            .with_body(format!(
+                r#"{{"data": {{"status": "running", "qmpstatus": "{}", "vmid": 101}}}}"#,
+                qmp_status
+            ))
+            .create_async()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_resume_paused_guest() {
+        let mut server = mockito::Server::new_async().await;
+        mock_qmp_status(&mut server, "paused").await;
+        let resume_request = server
+            .mock("POST", "/api2/json/nodes/pve1/qemu/101/status/resume")
+            .with_body(format!(r#"{{"data": "{}"}}"#, UPID))
+            .create_async()
+            .await;
+        server
+            .mock(
+                "GET",
+                format!("/api2/json/nodes/pve1/tasks/{}/status", UPID).as_str(),
+            )
+            .with_body(r#"{"data": {"status": "stopped", "exitstatus": "OK"}}"#)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Qemu, 101, 5000).await;
+        assert!(started.unwrap());
+        resume_request.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_start_guest_unexpected_state() {
+        let mut server = mockito::Server::new_async().await;
+        mock_qmp_status(&mut server, "prelaunch").await;
+        let request = server
+            .mock("POST", Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Qemu, 101, 5000).await;
+        assert!(matches!(
+            started,
+            Err(ProxmoxError::UnexpectedState(state)) if state == "prelaunch"
+        ));
+        request.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_start_guest_errors() {
+        let mut server = mockito::Server::new_async().await;
+        mock_status(
+            &mut server,
+            "/api2/json/nodes/pve1/qemu/101/status/current",
+            "stopped",
+        )
+        .await;
+        server
+            .mock("POST", "/api2/json/nodes/pve1/qemu/101/status/start")
+            .with_body(format!(r#"{{"data": "{}"}}"#, UPID))
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("/api2/json/nodes/pve1/tasks/{}/status", UPID).as_str())
+            .with_body(r#"{"data": {"status": "stopped", "exitstatus": "TASK ERROR: timeout waiting on systemd"}}"#)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Qemu, 101, 5000).await;
+        assert!(matches!(
+            started,
+            Err(ProxmoxError::TaskFailed(status)) if status.starts_with("TASK ERROR")
+        ));
+
+        server
+            .mock("GET", Matcher::Any)
+            .match_header("authorization", "PVEAPIToken=root@pam!rallyup=wrong")
+            .with_status(401)
+            .create_async()
+            .await;
+        let started = start(
+            &server.url(),
+            "root@pam!rallyup=wrong",
+            Guest::Qemu,
+            101,
+            5000,
+        )
+        .await;
+        assert!(matches!(started, Err(ProxmoxError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_start_guest_timeout() {
+        let mut server = mockito::Server::new_async().await;
+        mock_status(
+            &mut server,
+            "/api2/json/nodes/pve1/qemu/101/status/current",
+            "stopped",
+        )
+        .await;
+        server
+            .mock("POST", "/api2/json/nodes/pve1/qemu/101/status/start")
+            .with_body(format!(r#"{{"data": "{}"}}"#, UPID))
+            .create_async()
+            .await;
+        server
+            .mock(
+                "GET",
+                format!("/api2/json/nodes/pve1/tasks/{}/status", UPID).as_str(),
+            )
+            .with_body(r#"{"data": {"status": "running"}}"#)
+            .create_async()
+            .await;
+
+        let started = start(&server.url(), TOKEN, Guest::Qemu, 101, 200).await;
+        assert!(matches!(started, Err(ProxmoxError::TaskTimedOut(_))));
+    }
+}
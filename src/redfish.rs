@@ -0,0 +1,412 @@
+use crate::http::{self, ClientOptions, HttpError, HttpRequest};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+
+const SESSIONS_PATH: &str = "/redfish/v1/SessionService/Sessions";
+const SYSTEMS_PATH: &str = "/redfish/v1/Systems";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long a system that is shutting down gets to reach Off before giving up
+const POWERING_OFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum RedfishError {
+    #[error(transparent)]
+    RequestError(#[from] HttpError),
+
+    #[error("BMC login as {0} was rejected")]
+    AuthenticationFailed(String),
+
+    #[error("BMC returned {1} for {0}")]
+    UnexpectedStatus(String, u16),
+
+    #[error("Invalid response from the BMC for {0}")]
+    InvalidResponse(String),
+
+    #[error("BMC does not manage a system named {0}")]
+    SystemNotFound(String),
+
+    #[error("BMC manages {0} systems, pick one with `system`")]
+    AmbiguousSystem(usize),
+
+    #[error("System {0} is still powering off")]
+    StillPoweringOff(String),
+
+    #[error("BMC pointed to {0}, which is not on the BMC")]
+    ForeignUrl(String),
+}
+
+type Result<T> = std::result::Result<T, RedfishError>;
+
+pub struct Login<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+// What the BMC was asked to do
+#[derive(Debug, PartialEq, Eq)]
+pub enum PowerOnOutcome {
+    Started,
+    // The system was already on (or on its way), so it was left alone
+    AlreadyOn(String),
+}
+
+struct Session<'a> {
+    url: &'a str,
+    client: &'a ClientOptions,
+    token: Option<String>,
+    location: Option<String>,
+}
+
+impl Session<'_> {
+    // Resource paths (`@odata.id`) are absolute paths on the BMC. Full URLs are only followed
+    // when they point to the BMC itself, since the session token is sent along.
+    fn url_for(&self, path: &str) -> Result<String> {
+        if !(path.starts_with("http://") || path.starts_with("https://")) {
+            return Ok(format!("{}{}", self.url.trim_end_matches('/'), path));
+        }
+        let same_origin = match (reqwest::Url::parse(self.url), reqwest::Url::parse(path)) {
+            (Ok(base), Ok(url)) => base.origin() == url.origin(),
+            _ => false,
+        };
+        if !same_origin {
+            return Err(RedfishError::ForeignUrl(path.into()));
+        }
+        Ok(path.to_string())
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<reqwest::Response> {
+        let mut headers = HashMap::new();
+        if let Some(token) = &self.token {
+            headers.insert("X-Auth-Token".to_string(), token.clone());
+        }
+        if body.is_some() {
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+        }
+        let request = HttpRequest {
+            method: method.into(),
+            headers,
+            body: body.map(|body| body.to_string()),
+            auth: None,
+            client: self.client.clone(),
+        };
+        Ok(http::send(&self.url_for(path)?, &request).await?)
+    }
+
+    async fn get(&self, path: &str) -> Result<Value> {
+        let response = self.send("GET", path, None).await?;
+        if !response.status().is_success() {
+            return Err(RedfishError::UnexpectedStatus(
+                path.to_string(),
+                response.status().as_u16(),
+            ));
+        }
+        let body = response.text().await.map_err(HttpError::from)?;
+        serde_json::from_str(&body).map_err(|_| RedfishError::InvalidResponse(path.to_string()))
+    }
+
+    async fn login(&mut self, login: &Login<'_>) -> Result<()> {
+        let body = json!({"UserName": login.username, "Password": login.password});
+        let response = self.send("POST", SESSIONS_PATH, Some(body)).await?;
+        match response.status().as_u16() {
+            401 | 403 => return Err(RedfishError::AuthenticationFailed(login.username.into())),
+            status if !(200..300).contains(&status) => {
+                return Err(RedfishError::UnexpectedStatus(SESSIONS_PATH.into(), status))
+            }
+            _ => {}
+        }
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        self.token = Some(
+            header("X-Auth-Token")
+                .ok_or_else(|| RedfishError::InvalidResponse(SESSIONS_PATH.into()))?,
+        );
+        self.location = header("Location");
+        Ok(())
+    }
+
+    // BMCs only allow a handful of sessions at once, so don't leave them to expire
+    async fn logout(&self) {
+        if let Some(location) = &self.location {
+            let _ = self.send("DELETE", location, None).await;
+        }
+    }
+
+    async fn find_system(&self, system: Option<&str>) -> Result<String> {
+        let collection = self.get(SYSTEMS_PATH).await?;
+        let members: Vec<&str> = collection["Members"]
+            .as_array()
+            .ok_or_else(|| RedfishError::InvalidResponse(SYSTEMS_PATH.into()))?
+            .iter()
+            .filter_map(|member| member["@odata.id"].as_str())
+            .collect();
+
+        match (system, members.as_slice()) {
+            (Some(name), _) => members
+                .iter()
+                .find(|path| path.trim_end_matches('/').rsplit('/').next() == Some(name))
+                .map(|path| path.to_string())
+                .ok_or_else(|| RedfishError::SystemNotFound(name.into())),
+            (None, [only]) => Ok(only.to_string()),
+            (None, _) => Err(RedfishError::AmbiguousSystem(members.len())),
+        }
+    }
+
+    // The system resource, once it isn't on its way off anymore. A reset sent while the system
+    // shuts down would be ignored, and the system would end up off.
+    async fn settled_system(&self, path: &str) -> Result<(Value, String)> {
+        let deadline = tokio::time::Instant::now() + POWERING_OFF_TIMEOUT;
+        loop {
+            let resource = self.get(path).await?;
+            let power_state = resource["PowerState"]
+                .as_str()
+                .ok_or_else(|| RedfishError::InvalidResponse(path.into()))?
+                .to_string();
+            if power_state != "PoweringOff" {
+                return Ok((resource, power_state));
+            }
+            if tokio::time::Instant::now() + POLL_INTERVAL > deadline {
+                return Err(RedfishError::StillPoweringOff(path.into()));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn start_system(&self, system: Option<&str>) -> Result<PowerOnOutcome> {
+        let path = self.find_system(system).await?;
+        let (resource, power_state) = self.settled_system(&path).await?;
+        if power_state != "Off" {
+            return Ok(PowerOnOutcome::AlreadyOn(power_state));
+        }
+
+        let target = resource["Actions"]["#ComputerSystem.Reset"]["target"]
+            .as_str()
+            .map(|target| target.to_string())
+            .unwrap_or_else(|| format!("{}/Actions/ComputerSystem.Reset", path));
+        let response = self
+            .send("POST", &target, Some(json!({"ResetType": "On"})))
+            .await?;
+        if !response.status().is_success() {
+            return Err(RedfishError::UnexpectedStatus(
+                target,
+                response.status().as_u16(),
+            ));
+        }
+        Ok(PowerOnOutcome::Started)
+    }
+}
+
+// Turns the ComputerSystem on with ComputerSystem.Reset, but only if it is off, waiting for it
+// to get there when it is powering off. `system` is the id of the system, only needed when the
+// BMC manages more than one.
+pub async fn power_on(
+    url: &str,
+    client: &ClientOptions,
+    login: &Login<'_>,
+    system: Option<&str>,
+) -> Result<PowerOnOutcome> {
+    let mut session = Session {
+        url,
+        client,
+        token: None,
+        location: None,
+    };
+    session.login(login).await?;
+    let outcome = session.start_system(system).await;
+    session.logout().await;
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Mock, ServerGuard};
+
+    const LOGIN: Login = Login {
+        username: "root",
+        password: "calvin",
+    };
+
+    async fn mock_session(server: &mut ServerGuard) -> (Mock, Mock) {
+        let login = server
+            .mock("POST", SESSIONS_PATH)
+            .match_body(Matcher::Json(
+                json!({"UserName": "root", "Password": "calvin"}),
+            ))
+            .with_status(201)
+            .with_header("X-Auth-Token", "token")
+            .with_header("Location", "/redfish/v1/SessionService/Sessions/7")
+            .create_async()
+            .await;
+        let logout = server
+            .mock("DELETE", "/redfish/v1/SessionService/Sessions/7")
+            .match_header("x-auth-token", "token")
+            .with_status(204)
+            .create_async()
+            .await;
+        (login, logout)
+    }
+
+    async fn mock_system(server: &mut ServerGuard, power_state: &str) {
+        server
+            .mock("GET", SYSTEMS_PATH)
+            .match_header("x-auth-token", "token")
+            .with_body(r#"{"Members": [{"@odata.id": "/redfish/v1/Systems/System.Embedded.1"}]}"#)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/redfish/v1/Systems/System.Embedded.1")
+            .match_header("x-auth-token", "token")
+            .with_body(
+                json!({
+                    "PowerState": power_state,
+                    "Actions": {"#ComputerSystem.Reset": {
+                        "target": "/redfish/v1/Systems/System.Embedded.1/Actions/ComputerSystem.Reset"
+                    }}
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on() {
+        let mut server = mockito::Server::new_async().await;
+        let (login, logout) = mock_session(&mut server).await;
+        mock_system(&mut server, "Off").await;
+        let reset = server
+            .mock(
+                "POST",
+                "/redfish/v1/Systems/System.Embedded.1/Actions/ComputerSystem.Reset",
+            )
+            .match_header("x-auth-token", "token")
+            .match_body(Matcher::Json(json!({"ResetType": "On"})))
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let outcome = power_on(&server.url(), &ClientOptions::default(), &LOGIN, None).await;
+        assert_eq!(outcome.unwrap(), PowerOnOutcome::Started);
+        login.assert_async().await;
+        reset.assert_async().await;
+        logout.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_already_on() {
+        let mut server = mockito::Server::new_async().await;
+        let (_, logout) = mock_session(&mut server).await;
+        mock_system(&mut server, "On").await;
+        let reset = server
+            .mock("POST", Matcher::Regex("ComputerSystem.Reset".into()))
+            .expect(0)
+            .create_async()
+            .await;
+
+        let outcome = power_on(
+            &format!("{}/", server.url()),
+            &ClientOptions::default(),
+            &LOGIN,
+            Some("System.Embedded.1"),
+        )
+        .await;
+        assert_eq!(outcome.unwrap(), PowerOnOutcome::AlreadyOn("On".into()));
+        reset.assert_async().await;
+        logout.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_powering_off() {
+        let mut server = mockito::Server::new_async().await;
+        mock_session(&mut server).await;
+        // Answered once, before the system reaches Off
+        mock_system(&mut server, "PoweringOff").await;
+        mock_system(&mut server, "Off").await;
+        let reset = server
+            .mock(
+                "POST",
+                "/redfish/v1/Systems/System.Embedded.1/Actions/ComputerSystem.Reset",
+            )
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let outcome = power_on(&server.url(), &ClientOptions::default(), &LOGIN, None).await;
+        assert_eq!(outcome.unwrap(), PowerOnOutcome::Started);
+        reset.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_foreign_url() {
+        let mut server = mockito::Server::new_async().await;
+        mock_session(&mut server).await;
+        server
+            .mock("GET", SYSTEMS_PATH)
+            .with_body(r#"{"Members": [{"@odata.id": "http://192.0.2.1/redfish/v1/Systems/1"}]}"#)
+            .create_async()
+            .await;
+
+        let outcome = power_on(&server.url(), &ClientOptions::default(), &LOGIN, None).await;
+        assert!(
+            matches!(outcome, Err(RedfishError::ForeignUrl(url)) if url.starts_with("http://192.0.2.1/"))
+        );
+
+        // Full URLs on the BMC itself are fine
+        let session = Session {
+            url: &server.url(),
+            client: &ClientOptions::default(),
+            token: None,
+            location: None,
+        };
+        let own = format!("{}/redfish/v1/Systems/1", server.url());
+        assert_eq!(session.url_for(&own).unwrap(), own);
+    }
+
+    #[tokio::test]
+    async fn test_power_on_errors() {
+        let mut server = mockito::Server::new_async().await;
+        mock_session(&mut server).await;
+        server
+            .mock("GET", SYSTEMS_PATH)
+            .with_body(r#"{"Members": [{"@odata.id": "/redfish/v1/Systems/1"}, {"@odata.id": "/redfish/v1/Systems/2"}]}"#)
+            .create_async()
+            .await;
+
+        let client = ClientOptions::default();
+        assert!(matches!(
+            power_on(&server.url(), &client, &LOGIN, None).await,
+            Err(RedfishError::AmbiguousSystem(2))
+        ));
+        assert!(matches!(
+            power_on(&server.url(), &client, &LOGIN, Some("3")).await,
+            Err(RedfishError::SystemNotFound(_))
+        ));
+
+        let wrong_password = Login {
+            username: "root",
+            password: "hunter2",
+        };
+        server
+            .mock("POST", SESSIONS_PATH)
+            .with_status(401)
+            .create_async()
+            .await;
+        assert!(matches!(
+            power_on(&server.url(), &client, &wrong_password, None).await,
+            Err(RedfishError::AuthenticationFailed(_))
+        ));
+    }
+}
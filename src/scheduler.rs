@@ -0,0 +1,659 @@
+use crate::power::PowerOnBackend;
+use crate::servers::{self, CheckStatus, Server, ServerStatus};
+use crate::shutdown;
+use std::sync::Arc;
+use tokio::{sync::RwLock, task::JoinSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FailurePolicy {
+    /// Stop the whole run as soon as any server fails
+    #[default]
+    FailFast,
+    /// Keep going, and only skip the servers that require a server that failed
+    BestEffort,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Readiness {
+    Blocked,
+    Ready,
+    // Name of the required server that failed
+    Skip(String),
+}
+
+// The servers that have to finish before this one can start, and whether they are optional.
+// When waking up, these are the server's dependencies.
+// When shutting down, it is everything that depends on the server.
+fn blockers(servers: &[Server], index: usize, direction: Direction) -> Vec<(usize, bool)> {
+    match direction {
+        Direction::Up => servers[index]
+            .depends
+            .iter()
+            .filter_map(|dep| {
+                servers
+                    .iter()
+                    .position(|s| s.name == dep.name)
+                    .map(|i| (i, dep.optional))
+            })
+            .collect(),
+        Direction::Down => servers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| {
+                s.depends
+                    .iter()
+                    .find(|dep| dep.name == servers[index].name)
+                    .map(|dep| (i, dep.optional))
+            })
+            .collect(),
+    }
+}
+
+fn readiness(servers: &[Server], index: usize, direction: Direction) -> Readiness {
+    let mut readiness = Readiness::Ready;
+    for (blocker, optional) in blockers(servers, index, direction) {
+        let status = servers[blocker].status;
+        let done = match direction {
+            Direction::Up => status.is_up(),
+            Direction::Down => status == ServerStatus::Down,
+        };
+        if done || (optional && status.is_failed()) {
+            continue;
+        }
+        if status.is_failed() {
+            return Readiness::Skip(servers[blocker].name.clone());
+        }
+        readiness = Readiness::Blocked;
+    }
+    readiness
+}
+
+// Marks the servers that can no longer start because a server they require failed as skipped,
+// and returns the servers that are ready to start. Servers are started as soon as they are
+// returned here, so independent branches of the dependency graph are brought up (or shut down)
+// at the same time.
+fn schedule(servers: &mut [Server], started: &mut [bool], direction: Direction) -> Vec<usize> {
+    let mut ready = Vec::new();
+
+    // Skipping a server can cause the servers that require it to be skipped too
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in 0..servers.len() {
+            if started[index] {
+                continue;
+            }
+            match readiness(servers, index, direction) {
+                Readiness::Blocked => {}
+                Readiness::Ready => {
+                    started[index] = true;
+                    ready.push(index);
+                }
+                Readiness::Skip(blocker) => {
+                    started[index] = true;
+                    servers[index].status = ServerStatus::Skipped;
+                    servers[index].failure = Some(match direction {
+                        Direction::Up => format!("requires {}, which did not come up", blocker),
+                        Direction::Down => {
+                            format!("required by {}, which did not shut down", blocker)
+                        }
+                    });
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    ready
+}
+
+// Keep resending the power-on according to the server's resend policy,
+// until the first of its health checks passes
+async fn resend_power_on(servers: Arc<RwLock<Vec<Server>>>, server_index: usize) {
+    let (policy, can_resend) = {
+        let servers = servers.read().await;
+        let server = &servers[server_index];
+        (server.resend.clone(), server.power_on_method().can_resend())
+    };
+    if !can_resend {
+        return;
+    }
+
+    for _ in 0..policy.count {
+        tokio::time::sleep(policy.interval).await;
+
+        // Cloned so that slower methods don't hold the lock while they run
+        let server = servers.read().await[server_index].clone();
+        if server
+            .check
+            .iter()
+            .any(|check| matches!(check.status, CheckStatus::Ok))
+        {
+            return;
+        }
+
+        // The initial power-on went out fine, so failures here are most likely transient,
+        // and the health checks will time out if the server never comes up anyway. Methods
+        // that find the server already on didn't send anything, so that isn't counted.
+        if let Ok(true) = server.power_on().await {
+            servers.write().await[server_index].resends += 1;
+        }
+    }
+}
+
+// Everything needed to bring a single server up (or down), run as its own task so that
+// servers that are ready at the same time do not wait on each other
+async fn start_server(
+    servers: Arc<RwLock<Vec<Server>>>,
+    server_index: usize,
+    direction: Direction,
+) -> Result<ServerStatus, anyhow::Error> {
+    match direction {
+        Direction::Up => {
+            if servers::check_already_up(servers.clone(), server_index).await {
+                return Ok(ServerStatus::AlreadyUp);
+            }
+
+            let server = servers.read().await[server_index].clone();
+            let sent = server
+                .power_on()
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to power on {}: {}", server.name, e))?;
+            servers.write().await[server_index].status = if sent {
+                ServerStatus::PowerOnSent
+            } else {
+                ServerStatus::AlreadyOn
+            };
+
+            let resend_task = tokio::spawn(resend_power_on(servers.clone(), server_index));
+            let status = servers::perform_health_checks(servers, server_index).await;
+            resend_task.abort();
+
+            Ok(status)
+        }
+        Direction::Down => {
+            let (name, action) = {
+                let servers = servers.read().await;
+                let server = &servers[server_index];
+                (server.name.clone(), server.shutdown.clone())
+            };
+            // `shutdown_plan` only lets servers with a shutdown action through
+            let action =
+                action.ok_or_else(|| anyhow::anyhow!("{} has no shutdown action", name))?;
+            shutdown::send_shutdown(&action)
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to shut down {}: {}", name, e))?;
+            servers.write().await[server_index].status = ServerStatus::ShutdownSent;
+
+            Ok(servers::perform_down_checks(servers, server_index).await)
+        }
+    }
+}
+
+async fn run(
+    servers: Arc<RwLock<Vec<Server>>>,
+    direction: Direction,
+    policy: FailurePolicy,
+) -> Result<(), anyhow::Error> {
+    // Servers that are still up from an earlier pass of `watch_servers` are left alone
+    let mut started: Vec<bool> = servers
+        .read()
+        .await
+        .iter()
+        .map(|server| direction == Direction::Up && server.status.is_up())
+        .collect();
+    let mut tasks = JoinSet::new();
+
+    loop {
+        let ready = {
+            let mut servers = servers.write().await;
+            schedule(&mut servers, &mut started, direction)
+        };
+
+        for server_index in ready {
+            let servers = servers.clone();
+            tasks.spawn(async move {
+                let status = start_server(servers, server_index, direction).await;
+                (server_index, status)
+            });
+        }
+
+        // Wait for any of the servers in flight to finish its checks,
+        // since that might unblock the servers waiting on it
+        let Some(result) = tasks.join_next().await else {
+            break;
+        };
+
+        let (server_index, status) = result?;
+        let mut servers = servers.write().await;
+        let server = &mut servers[server_index];
+
+        let failure = match status {
+            Ok(ServerStatus::TimedOut) => match direction {
+                Direction::Up => format!("health check for {} timed out", server.name),
+                Direction::Down => format!(
+                    "{} did not shut down before its down checks timed out",
+                    server.name
+                ),
+            },
+            Ok(_) => continue,
+            Err(e) => {
+                server.status = ServerStatus::Failed;
+                e.to_string()
+            }
+        };
+        server.failure = Some(failure.clone());
+
+        if let FailurePolicy::FailFast = policy {
+            return Err(anyhow::anyhow!(failure));
+        }
+    }
+
+    let servers = servers.read().await;
+    let failed = servers
+        .iter()
+        .filter(|s| matches!(s.status, ServerStatus::TimedOut | ServerStatus::Failed))
+        .count();
+    let skipped = servers
+        .iter()
+        .filter(|s| s.status == ServerStatus::Skipped)
+        .count();
+    if failed > 0 || skipped > 0 {
+        return Err(anyhow::anyhow!(
+            "{} server(s) failed and {} were skipped",
+            failed,
+            skipped
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn wake_servers(
+    servers: Arc<RwLock<Vec<Server>>>,
+    policy: FailurePolicy,
+) -> Result<(), anyhow::Error> {
+    run(servers, Direction::Up, policy).await
+}
+
+// Expects the servers to be prepared with `servers::shutdown_plan`
+pub async fn shut_down_servers(
+    servers: Arc<RwLock<Vec<Server>>>,
+    policy: FailurePolicy,
+) -> Result<(), anyhow::Error> {
+    run(servers, Direction::Down, policy).await
+}
+
+// Keeps the servers up: every `interval`, each server's checks are run again in dependency
+// order. Servers that went down are woken up again, and the servers that depend on them are only
+// checked (and woken up if needed) once they are back. Failures never end the watch.
+pub async fn watch_servers(
+    servers: Arc<RwLock<Vec<Server>>>,
+    interval: std::time::Duration,
+) -> Result<(), anyhow::Error> {
+    loop {
+        // The failures are already recorded on the servers, and will be retried on the next pass
+        let _ = run(servers.clone(), Direction::Up, FailurePolicy::BestEffort).await;
+
+        tokio::time::sleep(interval).await;
+
+        let mut servers = servers.write().await;
+        for server in servers.iter_mut() {
+            // Without checks there is no way to tell that a server went down, so it would
+            // only be powered on again on every pass
+            if server.check.is_empty() && server.status.is_up() {
+                continue;
+            }
+            server.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML_DATA: &str = r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "hypervisor1"
+          mac: "11:22:33:44:55:66"
+          interface: "eth0"
+          depends:
+            - "storage"
+
+        - name: "hypervisor2"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+          depends:
+            - "storage"
+        "#;
+
+    #[test]
+    fn test_schedule() {
+        let mut servers: Vec<Server> =
+            serde_yaml_ng::from_str(YAML_DATA).expect("Failed to parse YAML");
+        let mut started = vec![false; servers.len()];
+
+        // Only storage has no dependencies
+        assert_eq!(schedule(&mut servers, &mut started, Direction::Up), vec![0]);
+
+        // Nothing else can start while storage is still booting
+        servers[0].status = ServerStatus::PowerOnSent;
+        assert!(schedule(&mut servers, &mut started, Direction::Up).is_empty());
+
+        // Both hypervisors can start together once storage is up
+        servers[0].status = ServerStatus::AlreadyUp;
+        assert_eq!(
+            schedule(&mut servers, &mut started, Direction::Up),
+            vec![1, 2]
+        );
+        assert_eq!(started, vec![true; 3]);
+    }
+
+    #[test]
+    fn test_schedule_shut_down() {
+        let mut servers: Vec<Server> =
+            serde_yaml_ng::from_str(YAML_DATA).expect("Failed to parse YAML");
+        let mut started = vec![false; servers.len()];
+
+        // Both hypervisors can be shut down right away, but storage has to wait for them
+        assert_eq!(
+            schedule(&mut servers, &mut started, Direction::Down),
+            vec![1, 2]
+        );
+
+        servers[1].status = ServerStatus::Down;
+        servers[2].status = ServerStatus::ShutdownSent;
+        assert!(schedule(&mut servers, &mut started, Direction::Down).is_empty());
+
+        servers[2].status = ServerStatus::Down;
+        assert_eq!(
+            schedule(&mut servers, &mut started, Direction::Down),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_schedule_failed_dependencies() {
+        let yaml_data = r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "backup"
+          mac: "11:22:33:44:55:66"
+          interface: "eth0"
+
+        - name: "hypervisor"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+          depends:
+            - "storage"
+            - name: "backup"
+              optional: true
+
+        - name: "vm"
+          mac: "33:44:55:66:77:88"
+          interface: "eth0"
+          depends:
+            - "hypervisor"
+        "#;
+
+        let mut servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let mut started = vec![false; servers.len()];
+
+        assert_eq!(
+            schedule(&mut servers, &mut started, Direction::Up),
+            vec![0, 1]
+        );
+
+        // The hypervisor still waits for the optional backup server to finish
+        servers[0].status = ServerStatus::Ok;
+        assert!(schedule(&mut servers, &mut started, Direction::Up).is_empty());
+
+        // but does not need it to come up
+        servers[1].status = ServerStatus::TimedOut;
+        assert_eq!(schedule(&mut servers, &mut started, Direction::Up), vec![2]);
+
+        // When a required dependency fails, everything downstream is skipped
+        servers[2].status = ServerStatus::Failed;
+        assert!(schedule(&mut servers, &mut started, Direction::Up).is_empty());
+        assert_eq!(servers[3].status, ServerStatus::Skipped);
+        assert_eq!(
+            servers[3].failure.as_deref(),
+            Some("requires hypervisor, which did not come up")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_best_effort() {
+        let yaml_data = r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          transport:
+            type: udp
+            address: 127.0.0.1
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+
+        - name: "backup"
+          mac: "11:22:33:44:55:66"
+          transport:
+            type: udp
+            address: 127.0.0.1
+          check:
+            - type: shell
+              command: "false"
+              status: 0
+              retry: 50ms
+              timeout: 200ms
+
+        - name: "hypervisor"
+          mac: "22:33:44:55:66:77"
+          depends:
+            - storage
+            - name: backup
+              optional: true
+          transport:
+            type: udp
+            address: 127.0.0.1
+
+        - name: "archive"
+          mac: "33:44:55:66:77:88"
+          depends:
+            - backup
+          transport:
+            type: udp
+            address: 127.0.0.1
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        let server_state = Arc::new(RwLock::new(servers.clone()));
+        let result = wake_servers(server_state.clone(), FailurePolicy::BestEffort).await;
+        assert!(result.is_err());
+
+        let statuses: Vec<ServerStatus> =
+            server_state.read().await.iter().map(|s| s.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ServerStatus::AlreadyUp,
+                ServerStatus::TimedOut,
+                ServerStatus::Ok,
+                ServerStatus::Skipped
+            ]
+        );
+
+        // Fail fast does not get to the hypervisor
+        let server_state = Arc::new(RwLock::new(servers));
+        let result = wake_servers(server_state.clone(), FailurePolicy::FailFast).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "health check for backup timed out"
+        );
+        assert_eq!(server_state.read().await[2].status, ServerStatus::Waiting);
+    }
+
+    #[tokio::test]
+    async fn test_resend_power_on() {
+        let listener = std::net::UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        listener
+            .set_read_timeout(Some(std::time::Duration::from_millis(500)))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          transport:
+            type: udp
+            address: 127.0.0.1
+            port: <port>
+          resend:
+            interval: 50ms
+            count: 3
+            burst: 2
+          check:
+            - type: shell
+              command: "false"
+              status: 0
+        "#;
+        let yaml_data = yaml_data.replace("<port>", &port.to_string());
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(&yaml_data).expect("Failed to parse YAML");
+        let server_state = Arc::new(RwLock::new(servers));
+
+        resend_power_on(server_state.clone(), 0).await;
+        assert_eq!(server_state.read().await[0].resends, 3);
+
+        // Each resend is a burst of 2 packets
+        let mut buffer = [0u8; 256];
+        for _ in 0..6 {
+            listener.recv_from(&mut buffer).unwrap();
+        }
+        assert!(listener.recv_from(&mut buffer).is_err());
+
+        // Stops as soon as one of the health checks passes
+        {
+            let mut servers = server_state.write().await;
+            servers[0].resends = 0;
+            servers[0].check[0].status = CheckStatus::Ok;
+        }
+        resend_power_on(server_state.clone(), 0).await;
+        assert_eq!(server_state.read().await[0].resends, 0);
+        assert!(listener.recv_from(&mut buffer).is_err());
+    }
+
+    // Counts the packets waiting on a nonblocking socket
+    fn receive_all(socket: &std::net::UdpSocket) -> usize {
+        let mut buffer = [0u8; 256];
+        std::iter::from_fn(|| socket.recv_from(&mut buffer).ok()).count()
+    }
+
+    // Polls in virtual time, so that the watch gets to run in between
+    async fn wait_until(
+        servers: &Arc<RwLock<Vec<Server>>>,
+        condition: impl Fn(&[Server]) -> bool,
+    ) -> bool {
+        for _ in 0..600 {
+            if condition(&servers.read().await) {
+                return true;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        false
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_servers() {
+        let wol1 = std::net::UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let wol2 = std::net::UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        wol1.set_nonblocking(true).unwrap();
+        wol2.set_nonblocking(true).unwrap();
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          transport:
+            type: udp
+            address: 127.0.0.1
+            port: <wol1>
+          check:
+            - type: port
+              host: 127.0.0.1
+              port: <port>
+
+        - name: "server2"
+          mac: "11:22:33:44:55:66"
+          transport:
+            type: udp
+            address: 127.0.0.1
+            port: <wol2>
+        "#;
+        let yaml_data = yaml_data
+            .replace("<wol1>", &wol1.local_addr().unwrap().port().to_string())
+            .replace("<wol2>", &wol2.local_addr().unwrap().port().to_string())
+            .replace("<port>", &address.port().to_string());
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(&yaml_data).expect("Failed to parse YAML");
+        let server_state = Arc::new(RwLock::new(servers));
+
+        let interval = std::time::Duration::from_secs(60);
+        let watch = tokio::spawn(watch_servers(server_state.clone(), interval));
+
+        // server1 is already up, so no WOL packet is sent, and server2 is woken up once
+        assert!(
+            wait_until(&server_state, |servers| servers[0].status
+                == ServerStatus::AlreadyUp
+                && servers[1].status.is_up())
+            .await
+        );
+        assert_eq!(receive_all(&wol1), 0);
+        assert!(receive_all(&wol2) > 0);
+
+        // server1 goes down and gets woken up again
+        drop(listener);
+        assert!(
+            wait_until(&server_state, |servers| servers[0].status
+                == ServerStatus::PowerOnSent)
+            .await
+        );
+        assert!(receive_all(&wol1) > 0);
+
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        assert!(
+            wait_until(&server_state, |servers| servers[0].status
+                == ServerStatus::Ok)
+            .await
+        );
+
+        // A few more passes, and server2 is still not woken up again
+        tokio::time::sleep(interval * 3).await;
+        assert!(server_state.read().await[0].status.is_up());
+        assert_eq!(receive_all(&wol2), 0);
+
+        watch.abort();
+        drop(listener);
+    }
+}
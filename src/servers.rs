@@ -1,13 +1,27 @@
+use crate::dns::{self, DnsProtocol};
+use crate::http::{self, HttpRequest};
+use crate::ipmi;
+use crate::json::JsonAssertion;
+use crate::nfs;
+use crate::ping;
+use crate::plugin;
+use crate::power::{PowerError, PowerOn, PowerOnBackend, Secret, WolPowerOn, WolTransport};
+use crate::process_group;
+use crate::shutdown::{self, ShutdownAction};
+use crate::smb;
+use crate::snmp;
+use crate::ssh;
+use crate::tcp;
+use crate::tls;
+use crate::wol;
 use colored::Colorize;
 use regex::Regex;
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     fmt, fs,
-    net::IpAddr,
-    process::Stdio,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Instant,
 };
 use tokio::{net::TcpStream, process::Command, sync::RwLock};
 
@@ -21,11 +35,20 @@ pub enum ServerConfigError {
     #[error("Found undefined dependency: {0}")]
     UndefinedDependency(String),
 
+    #[error("Unknown server: {0}")]
+    UnknownServer(String),
+
     #[error("Found circular dependency: {0}")]
     CircularDependency(String),
 
     #[error("Misconfigured healthcheck: {0}")]
     BadHealthCheckDefinition(String),
+
+    #[error("Misconfigured wake-up: {0}")]
+    BadWakeDefinition(String),
+
+    #[error("Misconfigured shutdown: {0}")]
+    BadShutdownDefinition(String),
 }
 
 fn default_retry_duration() -> std::time::Duration {
@@ -38,6 +61,54 @@ fn default_timeout_duration() -> std::time::Duration {
     std::time::Duration::from_secs(300)
 }
 
+fn default_ping_count() -> u16 {
+    1
+}
+
+fn default_ping_packet_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(1)
+}
+
+fn default_dns_port() -> u16 {
+    53
+}
+
+fn default_dns_record_type() -> String {
+    "A".into()
+}
+
+fn default_dns_rcode() -> String {
+    "NOERROR".into()
+}
+
+fn default_rpcbind_port() -> u16 {
+    111
+}
+
+fn default_smb_port() -> u16 {
+    445
+}
+
+fn default_read_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(5)
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_tls_port() -> u16 {
+    443
+}
+
+fn default_resend_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+fn default_resend_burst() -> u32 {
+    1
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum CheckStatus {
     #[default]
@@ -47,6 +118,31 @@ pub enum CheckStatus {
     Ok,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct CheckResult {
+    pub passed: bool,
+    // Why the check failed, or whatever else the check has to say about it
+    pub message: Option<String>,
+}
+
+impl CheckResult {
+    pub fn failed(message: impl Into<String>) -> Self {
+        CheckResult {
+            passed: false,
+            message: Some(message.into()),
+        }
+    }
+}
+
+impl From<bool> for CheckResult {
+    fn from(passed: bool) -> Self {
+        CheckResult {
+            passed,
+            message: None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct HealthCheck {
     #[serde(default = "default_retry_duration", with = "humantime_serde")]
@@ -55,16 +151,57 @@ pub struct HealthCheck {
     #[serde(default = "default_timeout_duration", with = "humantime_serde")]
     pub timeout: std::time::Duration,
 
+    // Limit on a single attempt, so that one hung connection or command does not use up
+    // the whole timeout. Attempts that take longer than this count as failed.
+    #[serde(default, with = "humantime_serde")]
+    pub attempt_timeout: Option<std::time::Duration>,
+
     #[serde(flatten)]
     pub method: HealthCheckMethod,
 
     #[serde(skip)]
     pub status: CheckStatus,
+
+    // Down checks pass when the underlying check fails, e.g. a port is closed
+    #[serde(skip)]
+    pub inverted: bool,
+
+    // Message from the latest attempt
+    #[serde(skip)]
+    pub message: Option<String>,
+}
+
+impl HealthCheck {
+    pub async fn attempt(&self) -> CheckResult {
+        let attempt = check_health(self.method.clone());
+        match self.attempt_timeout {
+            Some(attempt_timeout) => tokio::time::timeout(attempt_timeout, attempt)
+                .await
+                .unwrap_or_else(|_| CheckResult::failed("attempt timed out")),
+            None => attempt.await,
+        }
+    }
+
+    // A single attempt for one-off checks, which shouldn't wait out the whole check timeout on a
+    // server that never answers
+    pub async fn attempt_once(&self) -> CheckResult {
+        let limit = self
+            .attempt_timeout
+            .unwrap_or(ALREADY_UP_TIMEOUT)
+            .min(self.timeout);
+        tokio::time::timeout(limit, self.attempt())
+            .await
+            .unwrap_or_else(|_| CheckResult::failed(format!("timed out after {:?}", limit)))
+    }
 }
 
 impl fmt::Display for HealthCheck {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.method)
+        if self.inverted {
+            write!(f, "{} {}", "not".bold(), self.method)
+        } else {
+            write!(f, "{}", self.method)
+        }
     }
 }
 
@@ -76,10 +213,24 @@ pub enum HealthCheckMethod {
         status: Option<u16>,
         #[serde(default, with = "serde_regex")]
         regex: Option<Regex>,
+        // Assertions on the response body, which then has to be JSON
+        #[serde(default)]
+        json: Vec<JsonAssertion>,
+        #[serde(flatten)]
+        request: HttpRequest,
     },
     Port {
-        ip: String,
+        #[serde(alias = "ip")]
+        host: String,
         port: u16,
+        // Optional conversation, e.g. sending "PING\r\n" and expecting "+PONG"
+        send: Option<String>,
+        send_hex: Option<String>,
+        #[serde(default, with = "serde_regex")]
+        expect: Option<regex::bytes::Regex>,
+        expect_hex: Option<String>,
+        #[serde(default = "default_read_timeout", with = "humantime_serde")]
+        read_timeout: std::time::Duration,
     },
     Shell {
         command: String,
@@ -87,12 +238,91 @@ pub enum HealthCheckMethod {
         #[serde(default, with = "serde_regex")]
         regex: Option<Regex>,
     },
+    Ping {
+        ip: String,
+        #[serde(default = "default_ping_count")]
+        count: u16,
+        #[serde(default = "default_ping_packet_timeout", with = "humantime_serde")]
+        packet_timeout: std::time::Duration,
+        // Percentage of echo requests that may go unanswered
+        #[serde(default)]
+        max_loss: u8,
+    },
+    Dns {
+        server: String,
+        #[serde(default = "default_dns_port")]
+        port: u16,
+        name: String,
+        #[serde(default = "default_dns_record_type")]
+        record_type: String,
+        #[serde(default)]
+        protocol: DnsProtocol,
+        #[serde(default = "default_dns_rcode")]
+        rcode: String,
+        // Addresses that must all be in the answer
+        #[serde(default)]
+        addresses: Vec<IpAddr>,
+    },
+    Nfs {
+        ip: String,
+        // Port of rpcbind, nfsd and mountd ports are looked up from it
+        #[serde(default = "default_rpcbind_port")]
+        port: u16,
+        export: Option<String>,
+    },
+    Smb {
+        ip: String,
+        #[serde(default = "default_smb_port")]
+        port: u16,
+        // Oldest dialect the server may pick, e.g. "3.0"
+        min_dialect: Option<String>,
+    },
+    Ssh {
+        host: String,
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        // Without a command, the check only waits for the server's banner
+        command: Option<String>,
+        user: Option<String>,
+        identity: Option<String>,
+        passphrase: Option<Secret>,
+        // OpenSSH known_hosts file the server's host key must be in, ~/.ssh/known_hosts by default
+        known_hosts: Option<String>,
+        status: Option<i32>,
+        #[serde(default, with = "serde_regex")]
+        regex: Option<Regex>,
+    },
+    Tls {
+        host: String,
+        #[serde(default = "default_tls_port")]
+        port: u16,
+        // Name to ask for with SNI and verify the certificate against, defaults to the host
+        server_name: Option<String>,
+        #[serde(default)]
+        insecure_skip_verify: bool,
+        subject: Option<String>,
+        issuer: Option<String>,
+        min_days_valid: Option<u32>,
+        // SHA-256 fingerprint of the certificate
+        fingerprint: Option<String>,
+    },
+    Plugin {
+        // Name of an executable in the plugin path, or a path to one
+        plugin: String,
+        #[serde(default)]
+        params: serde_json::Map<String, serde_json::Value>,
+    },
 }
 
 fn truncate_command(command: &str, max_length: usize) -> String {
-    if command.len() > max_length {
-        // Truncate to 27 characters and add "..." to make it 30 characters in total
-        format!("{}{}", &command[..max_length - 3], "...".yellow())
+    if command.chars().count() > max_length {
+        // Truncate to 27 characters and add "..." to make it 30 characters in total, cutting
+        // between characters rather than bytes
+        let end = command
+            .char_indices()
+            .nth(max_length - 3)
+            .map_or(command.len(), |(end, _)| end);
+        format!("{}{}", &command[..end], "...".yellow())
     } else {
         command.to_string()
     }
@@ -101,19 +331,75 @@ fn truncate_command(command: &str, max_length: usize) -> String {
 impl fmt::Display for HealthCheckMethod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            HealthCheckMethod::Http {
-                url,
-                status: _,
-                regex: _,
-            } => write!(f, "{} [{}]", "http".bold(), url),
-            HealthCheckMethod::Port { ip, port } => {
-                write!(f, "{} [{}:{}]", "port".bold(), ip, port)
+            HealthCheckMethod::Http { url, .. } => write!(f, "{} [{}]", "http".bold(), url),
+            HealthCheckMethod::Port { host, port, .. } => {
+                write!(f, "{} [{}:{}]", "port".bold(), host, port)
             }
             HealthCheckMethod::Shell {
                 command,
                 status: _,
                 regex: _,
             } => write!(f, "{} [{}]", "shell".bold(), truncate_command(command, 30)),
+            HealthCheckMethod::Ping { ip, .. } => write!(f, "{} [{}]", "ping".bold(), ip),
+            HealthCheckMethod::Dns {
+                server,
+                name,
+                record_type,
+                ..
+            } => write!(f, "{} [{} {} @{}]", "dns".bold(), name, record_type, server),
+            HealthCheckMethod::Nfs { ip, export, .. } => match export {
+                Some(export) => write!(f, "{} [{}:{}]", "nfs".bold(), ip, export),
+                None => write!(f, "{} [{}]", "nfs".bold(), ip),
+            },
+            HealthCheckMethod::Smb { ip, port, .. } => {
+                write!(f, "{} [{}:{}]", "smb".bold(), ip, port)
+            }
+            HealthCheckMethod::Ssh {
+                host,
+                command: Some(command),
+                ..
+            } => write!(
+                f,
+                "{} [{}: {}]",
+                "ssh".bold(),
+                host,
+                truncate_command(command, 30)
+            ),
+            HealthCheckMethod::Ssh { host, port, .. } => {
+                write!(f, "{} [{}:{}]", "ssh".bold(), host, port)
+            }
+            HealthCheckMethod::Tls { host, port, .. } => {
+                write!(f, "{} [{}:{}]", "tls".bold(), host, port)
+            }
+            HealthCheckMethod::Plugin { plugin, .. } => {
+                write!(f, "{} [{}]", "plugin".bold(), plugin)
+            }
+        }
+    }
+}
+
+// How often the power-on is resent while waiting on a server's health checks, in case the first
+// WOL packet was dropped
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResendPolicy {
+    #[serde(default = "default_resend_interval", with = "humantime_serde")]
+    pub interval: std::time::Duration,
+
+    // Maximum number of times to resend, on top of the initial packet
+    #[serde(default)]
+    pub count: u32,
+
+    // Number of packets sent back to back every time
+    #[serde(default = "default_resend_burst")]
+    pub burst: u32,
+}
+
+impl Default for ResendPolicy {
+    fn default() -> Self {
+        ResendPolicy {
+            interval: default_resend_interval(),
+            count: 0,
+            burst: default_resend_burst(),
         }
     }
 }
@@ -122,26 +408,157 @@ impl fmt::Display for HealthCheckMethod {
 pub enum ServerStatus {
     #[default]
     Waiting,
-    WOLSent,
+    PowerOnSent,
+    // The power-on method found the server already on, and is waiting on its checks
+    AlreadyOn,
     Ok,
+    AlreadyUp,
     TimedOut,
+    Failed,
+    Skipped,
+    ShutdownSent,
+    Down,
+}
+
+impl ServerStatus {
+    pub fn is_up(&self) -> bool {
+        matches!(self, ServerStatus::Ok | ServerStatus::AlreadyUp)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            self,
+            ServerStatus::TimedOut | ServerStatus::Failed | ServerStatus::Skipped
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum DependencySpec {
+    Name(String),
+    Detailed {
+        name: String,
+        #[serde(default)]
+        optional: bool,
+    },
+}
+
+// Dependencies can be written as just the server name, or as `{ name, optional }`.
+// Optional dependencies are waited on, but the server is still started if they fail.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "DependencySpec")]
+pub struct Dependency {
+    pub name: String,
+    pub optional: bool,
+}
+
+impl From<DependencySpec> for Dependency {
+    fn from(spec: DependencySpec) -> Self {
+        match spec {
+            DependencySpec::Name(name) => Dependency {
+                name,
+                optional: false,
+            },
+            DependencySpec::Detailed { name, optional } => Dependency { name, optional },
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.optional {
+            write!(f, "{} (optional)", self.name)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub name: String,
-    pub mac: String,
-    pub interface: String,
+    // Servers without a power_on section are woken up with these WOL fields
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub interface: Option<String>,
     #[serde(default)]
     pub vlan: Option<u16>,
+    #[serde(default)]
+    pub transport: Option<WolTransport>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub power_on: Option<PowerOn>,
+    #[serde(default)]
+    pub resend: ResendPolicy,
 
     #[serde(default)]
-    pub depends: Vec<String>,
+    pub depends: Vec<Dependency>,
     #[serde(default)]
     pub check: Vec<HealthCheck>,
 
+    #[serde(default)]
+    pub shutdown: Option<ShutdownAction>,
+    #[serde(default)]
+    pub down: Vec<HealthCheck>,
+
     #[serde(skip)]
     pub status: ServerStatus,
+
+    // Number of times the power-on has been resent
+    #[serde(skip)]
+    pub resends: u32,
+
+    // Why the server failed or was skipped
+    #[serde(skip)]
+    pub failure: Option<String>,
+}
+
+impl Server {
+    // Forget the progress of the previous run, so the server can be brought up again
+    pub fn reset(&mut self) {
+        self.status = ServerStatus::Waiting;
+        self.resends = 0;
+        self.failure = None;
+        for check in self.check.iter_mut() {
+            check.status = CheckStatus::Waiting;
+            check.message = None;
+        }
+    }
+
+    pub fn power_on_method(&self) -> PowerOn {
+        match &self.power_on {
+            Some(method) => method.clone(),
+            None => PowerOn::Wol(WolPowerOn {
+                // Servers without either are rejected when the config is validated
+                mac: self.mac.clone().unwrap_or_default(),
+                interface: self.interface.clone(),
+                vlan: self.vlan,
+                transport: self.transport.clone().unwrap_or_default(),
+                password: self.password.clone(),
+            }),
+        }
+    }
+
+    // Returns false when the server was found already on, so nothing was sent
+    pub async fn power_on(&self) -> Result<bool, PowerError> {
+        let method = self.power_on_method();
+        // Only WOL packets can get lost on the way, so they are the only thing sent in bursts
+        let burst = match method {
+            PowerOn::Wol(_) => self.resend.burst,
+            _ => 1,
+        };
+        let timeout = method.timeout();
+        let mut sent = false;
+        for _ in 0..burst {
+            sent |= tokio::time::timeout(timeout, method.power_on())
+                .await
+                .map_err(|_| PowerError::TimedOut(timeout))??;
+        }
+        Ok(sent)
+    }
 }
 
 fn map_server_names(servers: &[Server]) -> HashMap<String, &Server> {
@@ -149,13 +566,40 @@ fn map_server_names(servers: &[Server]) -> HashMap<String, &Server> {
 }
 
 fn determine_wakeup_order(servers: &[Server]) -> Result<Vec<Server>, ServerConfigError> {
+    wakeup_order_from(servers, servers)
+}
+
+// Wake-up order for only the given servers and everything they transitively depend on
+pub fn select_servers(
+    servers: &[Server],
+    targets: &[String],
+) -> Result<Vec<Server>, ServerConfigError> {
+    let server_from_name = map_server_names(servers);
+
+    let roots = targets
+        .iter()
+        .map(|target| {
+            server_from_name
+                .get(target)
+                .copied()
+                .ok_or_else(|| ServerConfigError::UnknownServer(target.clone()))
+        })
+        .collect::<Result<Vec<&Server>, ServerConfigError>>()?;
+
+    wakeup_order_from(servers, roots)
+}
+
+fn wakeup_order_from<'a>(
+    servers: &[Server],
+    roots: impl IntoIterator<Item = &'a Server>,
+) -> Result<Vec<Server>, ServerConfigError> {
     let server_from_name = map_server_names(servers);
 
     let mut visited = HashSet::new();
     let mut visiting = HashSet::new();
     let mut sorted = Vec::new();
 
-    for server in servers {
+    for server in roots {
         if !visited.contains(&server.name) {
             depth_first_search(
                 server,
@@ -194,8 +638,8 @@ fn depth_first_search(
 
     for dep in &server.depends {
         let dep_server = server_from_name
-            .get(dep)
-            .ok_or_else(|| ServerConfigError::UndefinedDependency(dep.clone()))?;
+            .get(&dep.name)
+            .ok_or_else(|| ServerConfigError::UndefinedDependency(dep.name.clone()))?;
         depth_first_search(dep_server, server_from_name, visited, visiting, sorted)?;
     }
 
@@ -213,15 +657,51 @@ fn validate_health_check(healthcheck: &HealthCheckMethod) -> Result<(), ServerCo
             url: _,
             status,
             regex,
+            json,
+            request,
         } => {
-            if status.is_none() && regex.is_none() {
-                return Err(ServerConfigError::BadHealthCheckDefinition("HTTP health check requires an HTTP status code to match and/or a Regex to match in the response".into()));
+            if status.is_none() && regex.is_none() && json.is_empty() {
+                return Err(ServerConfigError::BadHealthCheckDefinition("HTTP health check requires an HTTP status code, a Regex, and/or JSON assertions to match in the response".into()));
             }
+            for assertion in json {
+                assertion
+                    .validate()
+                    .map_err(|e| ServerConfigError::BadHealthCheckDefinition(e.to_string()))?;
+            }
+            http::validate_request(request)
+                .map_err(|e| ServerConfigError::BadHealthCheckDefinition(e.to_string()))?;
         }
-        HealthCheckMethod::Port { ip, port: _ } => {
-            if ip.parse::<IpAddr>().is_err() {
+        HealthCheckMethod::Port {
+            host,
+            port: _,
+            send,
+            send_hex,
+            expect,
+            expect_hex,
+            read_timeout: _,
+        } => {
+            if !tcp::is_valid_host(host) {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Port check requires a valid IP address or hostname".into(),
+                ));
+            }
+            if send.is_some() && send_hex.is_some() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Port check can only send one of send and send_hex".into(),
+                ));
+            }
+            if expect.is_some() && expect_hex.is_some() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Port check can only expect one of expect and expect_hex".into(),
+                ));
+            }
+            if [send_hex, expect_hex]
+                .into_iter()
+                .flatten()
+                .any(|hex| tcp::parse_hex(hex).is_none())
+            {
                 return Err(ServerConfigError::BadHealthCheckDefinition(
-                    "Port check requires a valid IP address".into(),
+                    "Port check has invalid hex bytes".into(),
                 ));
             }
         }
@@ -234,108 +714,830 @@ fn validate_health_check(healthcheck: &HealthCheckMethod) -> Result<(), ServerCo
                 return Err(ServerConfigError::BadHealthCheckDefinition("Health check via shell command requires an return code to match and/or a Regex to match in the standard output".into()));
             }
         }
+        HealthCheckMethod::Ping {
+            ip,
+            count,
+            packet_timeout: _,
+            max_loss,
+        } => {
+            if ip.parse::<IpAddr>().is_err() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Ping check requires a valid IP address".into(),
+                ));
+            }
+            if *count == 0 {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Ping check needs to send at least 1 packet".into(),
+                ));
+            }
+            if *max_loss > 100 {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "Ping check packet loss threshold is a percentage between 0 and 100".into(),
+                ));
+            }
+        }
+        HealthCheckMethod::Dns {
+            server,
+            port: _,
+            name,
+            record_type,
+            protocol: _,
+            rcode,
+            addresses,
+        } => {
+            if server.parse::<IpAddr>().is_err() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "DNS check requires the IP address of the DNS server".into(),
+                ));
+            }
+            if dns::encode_name(name).is_err() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(format!(
+                    "DNS check has an invalid name to query: {}",
+                    name
+                )));
+            }
+            let Some(record_type) = dns::record_type_from_str(record_type) else {
+                return Err(ServerConfigError::BadHealthCheckDefinition(format!(
+                    "DNS check has an unsupported record type: {}",
+                    record_type
+                )));
+            };
+            if dns::rcode_from_str(rcode).is_none() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(format!(
+                    "DNS check has an unknown response code: {}",
+                    rcode
+                )));
+            }
+            if !addresses.is_empty() && record_type != dns::TYPE_A && record_type != dns::TYPE_AAAA
+            {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "DNS check can only match addresses for A and AAAA records".into(),
+                ));
+            }
+        }
+        HealthCheckMethod::Nfs {
+            ip,
+            port: _,
+            export,
+        } => {
+            if ip.parse::<IpAddr>().is_err() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "NFS check requires a valid IP address".into(),
+                ));
+            }
+            if export
+                .as_ref()
+                .is_some_and(|export| !export.starts_with('/'))
+            {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "NFS check export must be an absolute path".into(),
+                ));
+            }
+        }
+        HealthCheckMethod::Smb {
+            ip,
+            port: _,
+            min_dialect,
+        } => {
+            if ip.parse::<IpAddr>().is_err() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "SMB check requires a valid IP address".into(),
+                ));
+            }
+            if let Some(min_dialect) = min_dialect {
+                if smb::dialect_from_str(min_dialect).is_none() {
+                    return Err(ServerConfigError::BadHealthCheckDefinition(format!(
+                        "SMB check has an unknown dialect: {}, expected one of {}",
+                        min_dialect,
+                        smb::DIALECTS
+                            .iter()
+                            .map(|(name, _)| *name)
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    )));
+                }
+            }
+        }
+        HealthCheckMethod::Ssh {
+            host,
+            port: _,
+            command,
+            user,
+            identity,
+            passphrase,
+            known_hosts: _,
+            status,
+            regex,
+        } => {
+            if host.is_empty() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "SSH check requires a host to connect to".into(),
+                ));
+            }
+            if let Some(passphrase) = passphrase {
+                passphrase
+                    .read()
+                    .map_err(|e| ServerConfigError::BadHealthCheckDefinition(e.to_string()))?;
+            }
+            if command.is_none() {
+                if status.is_some() || regex.is_some() {
+                    return Err(ServerConfigError::BadHealthCheckDefinition(
+                        "SSH check can only match a status or Regex when running a command".into(),
+                    ));
+                }
+            } else {
+                if user.is_none() || identity.is_none() {
+                    return Err(ServerConfigError::BadHealthCheckDefinition(
+                        "SSH check requires a user and identity file to run a command".into(),
+                    ));
+                }
+                if status.is_none() && regex.is_none() {
+                    return Err(ServerConfigError::BadHealthCheckDefinition("SSH check requires a return code to match and/or a Regex to match in the standard output of the command".into()));
+                }
+            }
+        }
+        HealthCheckMethod::Tls {
+            host, fingerprint, ..
+        } => {
+            if host.is_empty() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "TLS check requires a host to connect to".into(),
+                ));
+            }
+            if fingerprint
+                .as_ref()
+                .is_some_and(|fingerprint| tls::parse_fingerprint(fingerprint).is_none())
+            {
+                return Err(ServerConfigError::BadHealthCheckDefinition(
+                    "TLS check fingerprint should be a SHA-256 hash in hex".into(),
+                ));
+            }
+        }
+        HealthCheckMethod::Plugin { plugin, params } => {
+            plugin::validate_plugin(plugin, params)
+                .map_err(|e| ServerConfigError::BadHealthCheckDefinition(e.to_string()))?;
+        }
     }
 
     Ok(())
 }
 
-pub fn parse_server_dependencies(file_path: &str) -> Result<Vec<Server>, ServerConfigError> {
-    let yaml_content =
-        fs::read_to_string(file_path).map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
-
-    let servers: Vec<Server> = serde_yaml_ng::from_str(&yaml_content)
-        .map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
+fn validate_wol(name: &str, wol: &WolPowerOn) -> Result<(), ServerConfigError> {
+    if let Some(password) = &wol.password {
+        wol::parse_secureon_password(password).map_err(|_| {
+            ServerConfigError::BadWakeDefinition(format!(
+                "{} has an invalid SecureOn password, expected 4 or 6 bytes in hex",
+                name
+            ))
+        })?;
+    }
 
-    for server in &servers {
-        for healthcheck in &server.check {
-            validate_health_check(&healthcheck.method)?;
+    match wol.transport {
+        WolTransport::Ethernet => {
+            if wol.interface.is_none() {
+                return Err(ServerConfigError::BadWakeDefinition(format!(
+                    "{} needs a network interface to send the WOL packet from",
+                    name
+                )));
+            }
+        }
+        WolTransport::Udp { .. } => {
+            if wol.vlan.is_some() {
+                return Err(ServerConfigError::BadWakeDefinition(format!(
+                    "{} sets a VLAN, which is only supported by the ethernet transport",
+                    name
+                )));
+            }
         }
     }
 
-    // Apply topological sort to determine order to wake the servers
-    // check for circular and undefined servers along the way
-    let sorted = determine_wakeup_order(&servers)?;
-
-    Ok(sorted)
+    Ok(())
 }
 
-async fn http_health_check(
-    url: &str,
-    expected_status: Option<u16>,
-    payload_regex: Option<Regex>,
-) -> bool {
-    if let Ok(response) = reqwest::get(url).await {
-        if let Some(status) = expected_status {
-            if response.status().as_u16() != status {
-                return false;
-            }
+fn validate_power_on(server: &Server) -> Result<(), ServerConfigError> {
+    let bad_definition =
+        |reason: &str| ServerConfigError::BadWakeDefinition(format!("{} {}", server.name, reason));
+
+    let has_wol_fields = server.mac.is_some()
+        || server.interface.is_some()
+        || server.vlan.is_some()
+        || server.transport.is_some()
+        || server.password.is_some();
+    match &server.power_on {
+        None if server.mac.is_none() => {
+            return Err(bad_definition("needs a MAC address or a power_on method"));
         }
-        if let Some(regex) = payload_regex {
-            if let Ok(body) = response.text().await {
-                if regex.is_match(&body) {
-                    return true;
-                }
-            };
-            return false;
+        Some(_) if has_wol_fields => {
+            return Err(bad_definition(
+                "sets both a power_on method and top level WOL fields",
+            ));
         }
-        return true;
-    };
-    false
-}
-
-async fn port_health_check(ip: &str, port: u16) -> bool {
-    let address = format!("{}:{}", ip, port);
-    TcpStream::connect(address).await.is_ok()
-}
+        _ => {}
+    }
 
-async fn shell_health_check(
-    command: &str,
-    expected_status: Option<i32>,
-    payload_regex: Option<Regex>,
-) -> bool {
-    let result = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await;
+    let method = server.power_on_method();
+    match &method {
+        PowerOn::Wol(wol) => validate_wol(&server.name, wol)?,
+        PowerOn::Shell(_) => {}
+        PowerOn::Http(http) => {
+            http::validate_request(&http.request())
+                .map_err(|e| bad_definition(&format!("has an invalid power-on request: {}", e)))?;
+        }
+        PowerOn::Redfish(redfish) => {
+            if reqwest::Url::parse(&redfish.url).is_err() {
+                return Err(bad_definition("has an invalid Redfish URL"));
+            }
+            let request = HttpRequest {
+                client: redfish.client.clone(),
+                ..Default::default()
+            };
+            http::validate_request(&request).map_err(|e| bad_definition(&e.to_string()))?;
+            redfish
+                .password
+                .read()
+                .map_err(|e| bad_definition(&e.to_string()))?;
+        }
+        PowerOn::Ipmi(ipmi) => {
+            if !tcp::is_valid_host(&ipmi.address) {
+                return Err(bad_definition("has an invalid BMC address"));
+            }
+            if ipmi::cipher_suite(ipmi.cipher_suite).is_none() {
+                return Err(bad_definition(&format!(
+                    "uses cipher suite {}, expected one of 1, 2, 3, 15, 16 or 17",
+                    ipmi.cipher_suite
+                )));
+            }
+            if ipmi.username.len() > ipmi::MAX_USERNAME_LENGTH {
+                return Err(bad_definition("has an IPMI username over 16 characters"));
+            }
+            ipmi.password
+                .read()
+                .map_err(|e| bad_definition(&e.to_string()))?;
+        }
+        PowerOn::Proxmox(proxmox) => {
+            if reqwest::Url::parse(&proxmox.url).is_err() {
+                return Err(bad_definition("has an invalid Proxmox API URL"));
+            }
+            if proxmox.node.is_empty() || proxmox.node.contains('/') {
+                return Err(bad_definition("has an invalid Proxmox node name"));
+            }
+            let request = HttpRequest {
+                client: proxmox.client.clone(),
+                ..Default::default()
+            };
+            http::validate_request(&request).map_err(|e| bad_definition(&e.to_string()))?;
+            proxmox
+                .token
+                .read()
+                .map_err(|e| bad_definition(&e.to_string()))?;
+        }
+        PowerOn::Snmp(snmp) => {
+            if !tcp::is_valid_host(&snmp.address) {
+                return Err(bad_definition("has an invalid SNMP agent address"));
+            }
+            if snmp::parse_oid(&snmp.oid).is_none() {
+                return Err(bad_definition(&format!("has an invalid OID: {}", snmp.oid)));
+            }
+            if snmp.cycle.is_some() && snmp.off_value.is_none() {
+                return Err(bad_definition(
+                    "needs an off_value to power cycle the outlet",
+                ));
+            }
+            snmp.community
+                .read()
+                .map_err(|e| bad_definition(&e.to_string()))?;
+        }
+        PowerOn::Plug(plug) => {
+            if reqwest::Url::parse(&plug.url).is_err() {
+                return Err(bad_definition("has an invalid plug URL"));
+            }
+            let request = HttpRequest {
+                client: plug.client.clone(),
+                ..Default::default()
+            };
+            http::validate_request(&request).map_err(|e| bad_definition(&e.to_string()))?;
+            if let Some(password) = &plug.password {
+                password
+                    .read()
+                    .map_err(|e| bad_definition(&e.to_string()))?;
+            }
+        }
+    }
 
-    if let Ok(output) = result {
-        if let Some(status) = expected_status {
-            if output.status.code() != Some(status) {
-                return false;
+    if server.resend.burst == 0 {
+        return Err(bad_definition("needs to send at least 1 packet per burst"));
+    }
+
+    if server.resend.count > 0 {
+        if !method.can_resend() {
+            return Err(bad_definition(
+                "uses a power-on method that can't be resent",
+            ));
+        }
+        if server.resend.interval.is_zero() {
+            return Err(bad_definition("needs a non-zero interval between resends"));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_shutdown(server: &Server) -> Result<(), ServerConfigError> {
+    if let Some(action) = &server.shutdown {
+        shutdown::validate_shutdown_action(action).map_err(|e| {
+            ServerConfigError::BadShutdownDefinition(format!("{}: {}", server.name, e))
+        })?;
+    }
+
+    for check in &server.down {
+        validate_health_check(&check.method)?;
+    }
+
+    Ok(())
+}
+
+pub fn parse_server_dependencies(file_path: &str) -> Result<Vec<Server>, ServerConfigError> {
+    let yaml_content =
+        fs::read_to_string(file_path).map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
+
+    let servers: Vec<Server> = serde_yaml_ng::from_str(&yaml_content)
+        .map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
+
+    for server in &servers {
+        validate_power_on(server)?;
+        validate_shutdown(server)?;
+        for healthcheck in &server.check {
+            validate_health_check(&healthcheck.method)?;
+        }
+    }
+
+    // Apply topological sort to determine order to wake the servers
+    // check for circular and undefined servers along the way
+    let sorted = determine_wakeup_order(&servers)?;
+
+    Ok(sorted)
+}
+
+// Servers are shut down in the reverse of the order they are woken up in, and the down checks
+// take the place of the regular checks so that the progress renderer can display them as usual
+pub fn shutdown_plan(wake_order: Vec<Server>) -> Result<Vec<Server>, ServerConfigError> {
+    wake_order
+        .into_iter()
+        .rev()
+        .map(|mut server| {
+            if server.shutdown.is_none() {
+                return Err(ServerConfigError::BadShutdownDefinition(format!(
+                    "{} does not define a shutdown action",
+                    server.name
+                )));
             }
+            // Without down checks there would be no way to tell that the server is off
+            if server.down.is_empty() {
+                return Err(ServerConfigError::BadShutdownDefinition(format!(
+                    "{} does not define any down checks",
+                    server.name
+                )));
+            }
+            server.check = std::mem::take(&mut server.down);
+            for check in server.check.iter_mut() {
+                check.inverted = true;
+            }
+            Ok(server)
+        })
+        .collect()
+}
+
+async fn http_health_check(
+    url: &str,
+    request: &HttpRequest,
+    expected_status: Option<u16>,
+    payload_regex: Option<Regex>,
+    json: &[JsonAssertion],
+) -> CheckResult {
+    let response = match http::send(url, request).await {
+        Ok(response) => response,
+        Err(e) => return CheckResult::failed(e.to_string()),
+    };
+
+    if let Some(status) = expected_status {
+        if response.status().as_u16() != status {
+            return CheckResult::failed(format!("unexpected status {}", response.status()));
         }
+    }
+    if payload_regex.is_none() && json.is_empty() {
+        return true.into();
+    }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if let Some(regex) = payload_regex {
-            if !regex.is_match(&stdout) {
-                return false;
+    let Ok(body) = response.text().await else {
+        return CheckResult::failed("failed to read the response body");
+    };
+    if let Some(regex) = payload_regex {
+        if !regex.is_match(&body) {
+            return CheckResult::failed("response did not match the regex");
+        }
+    }
+    if !json.is_empty() {
+        let document = match serde_json::from_str(&body) {
+            Ok(document) => document,
+            Err(e) => return CheckResult::failed(format!("response is not JSON: {}", e)),
+        };
+        for assertion in json {
+            if let Err(message) = assertion.check(&document) {
+                return CheckResult::failed(message);
             }
         }
-        return true;
+    }
+    true.into()
+}
+
+async fn port_health_check(host: &str, port: u16) -> bool {
+    TcpStream::connect((host, port)).await.is_ok()
+}
+
+async fn tcp_health_check(
+    host: &str,
+    port: u16,
+    send: Option<&[u8]>,
+    expectation: Option<tcp::Expectation<'_>>,
+    read_timeout: std::time::Duration,
+) -> CheckResult {
+    match tcp::converse(host, port, send, expectation, read_timeout).await {
+        Ok(()) => true.into(),
+        Err(message) => CheckResult::failed(message),
+    }
+}
+
+fn command_output_matches(
+    status: Option<i32>,
+    stdout: &str,
+    expected_status: Option<i32>,
+    payload_regex: Option<&Regex>,
+) -> CheckResult {
+    if let Some(expected) = expected_status {
+        if status != Some(expected) {
+            return CheckResult::failed(match status {
+                Some(status) => format!("exited with status {}", status),
+                None => "killed by a signal".into(),
+            });
+        }
+    }
+    if let Some(regex) = payload_regex {
+        if !regex.is_match(stdout) {
+            return CheckResult::failed("output did not match the regex");
+        }
+    }
+    true.into()
+}
+
+async fn shell_health_check(
+    command: &str,
+    expected_status: Option<i32>,
+    payload_regex: Option<Regex>,
+) -> bool {
+    // Kill the command if the attempt is cancelled because it took too long
+    let result = process_group::output(Command::new("sh").arg("-c").arg(command)).await;
+
+    if let Ok(output) = result {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        return command_output_matches(
+            output.status.code(),
+            &stdout,
+            expected_status,
+            payload_regex.as_ref(),
+        )
+        .passed;
     };
     false
 }
 
-pub async fn check_health(check: HealthCheckMethod) -> bool {
+// A command for an SSH check to run, and what it should return
+struct SshCommand<'a> {
+    command: &'a str,
+    known_hosts: Option<&'a str>,
+    login: ssh::Login<'a>,
+    status: Option<i32>,
+    regex: Option<&'a Regex>,
+}
+
+async fn ssh_health_check(host: &str, port: u16, command: Option<SshCommand<'_>>) -> CheckResult {
+    let Some(command) = command else {
+        return match ssh::read_banner(host, port).await {
+            Ok(banner) => CheckResult {
+                passed: true,
+                message: Some(banner),
+            },
+            Err(e) => CheckResult::failed(e.to_string()),
+        };
+    };
+
+    let known_hosts = match command.known_hosts {
+        Some(path) => std::path::PathBuf::from(path),
+        None => match ssh::default_known_hosts() {
+            Some(path) => path,
+            None => return CheckResult::failed("HOME is not set, set known_hosts"),
+        },
+    };
+    match ssh::run_command(host, port, &known_hosts, &command.login, command.command).await {
+        Ok(output) => command_output_matches(
+            Some(output.status),
+            &output.stdout,
+            command.status,
+            command.regex,
+        ),
+        Err(e) => CheckResult::failed(e.to_string()),
+    }
+}
+
+async fn ping_health_check(
+    ip: &str,
+    count: u16,
+    packet_timeout: std::time::Duration,
+    max_loss: u8,
+) -> bool {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return false;
+    };
+    // The ICMP socket is blocking, but is bounded by the packet timeout
+    tokio::task::spawn_blocking(move || ping::ping(ip, count, packet_timeout, max_loss))
+        .await
+        .unwrap_or(false)
+}
+
+async fn dns_health_check(
+    server: &str,
+    port: u16,
+    name: &str,
+    record_type: &str,
+    protocol: DnsProtocol,
+    expected_rcode: &str,
+    expected_addresses: &[IpAddr],
+) -> bool {
+    let (Ok(ip), Some(record_type), Some(expected_rcode)) = (
+        server.parse::<IpAddr>(),
+        dns::record_type_from_str(record_type),
+        dns::rcode_from_str(expected_rcode),
+    ) else {
+        return false;
+    };
+
+    match dns::query(SocketAddr::new(ip, port), name, record_type, protocol).await {
+        Ok(response) => {
+            response.rcode == expected_rcode
+                && expected_addresses
+                    .iter()
+                    .all(|address| response.addresses.contains(address))
+        }
+        Err(_) => false,
+    }
+}
+
+async fn nfs_health_check(ip: &str, port: u16, export: Option<&str>) -> bool {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return false;
+    };
+
+    match nfs::list_exports(ip, port).await {
+        Ok(exports) => export.is_none_or(|export| {
+            exports
+                .iter()
+                .any(|path| path.trim_end_matches('/') == export.trim_end_matches('/'))
+        }),
+        Err(_) => false,
+    }
+}
+
+async fn smb_health_check(ip: &str, port: u16, min_dialect: Option<&str>) -> bool {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return false;
+    };
+    let min_dialect = match min_dialect {
+        Some(min_dialect) => match smb::dialect_from_str(min_dialect) {
+            Some(min_dialect) => min_dialect,
+            None => return false,
+        },
+        None => 0,
+    };
+
+    match smb::negotiate(SocketAddr::new(ip, port)).await {
+        Ok(dialect) => dialect >= min_dialect,
+        Err(_) => false,
+    }
+}
+
+async fn tls_health_check(
+    target: &tls::Target<'_>,
+    expected: &tls::Expectations<'_>,
+) -> CheckResult {
+    let certificate = match tls::fetch_certificate(target).await {
+        Ok(certificate) => certificate,
+        Err(e) => return CheckResult::failed(e.to_string()),
+    };
+
+    match tls::check_certificate(&certificate, expected) {
+        Ok(()) => true.into(),
+        Err(message) => CheckResult::failed(message),
+    }
+}
+
+async fn plugin_health_check(
+    plugin: &str,
+    params: &serde_json::Map<String, serde_json::Value>,
+) -> CheckResult {
+    match plugin::run_plugin(plugin, params).await {
+        Ok(result) => CheckResult {
+            passed: result.ok,
+            message: result.summary(),
+        },
+        Err(e) => CheckResult::failed(e.to_string()),
+    }
+}
+
+pub async fn check_health(check: HealthCheckMethod) -> CheckResult {
     match check {
-        HealthCheckMethod::Http { url, status, regex } => {
-            http_health_check(&url, status, regex).await
+        HealthCheckMethod::Http {
+            url,
+            status,
+            regex,
+            json,
+            request,
+        } => http_health_check(&url, &request, status, regex, &json).await,
+        HealthCheckMethod::Port {
+            host,
+            port,
+            send,
+            send_hex,
+            expect,
+            expect_hex,
+            read_timeout,
+        } => {
+            if send.is_none() && send_hex.is_none() && expect.is_none() && expect_hex.is_none() {
+                return port_health_check(&host, port).await.into();
+            }
+
+            let send_hex = send_hex.as_deref().and_then(tcp::parse_hex);
+            let payload = send
+                .as_ref()
+                .map(|send| send.as_bytes())
+                .or(send_hex.as_deref());
+            let expect_hex = expect_hex.as_deref().and_then(tcp::parse_hex);
+            let expectation = match (&expect, &expect_hex) {
+                (Some(regex), _) => Some(tcp::Expectation::Regex(regex)),
+                (None, Some(bytes)) => Some(tcp::Expectation::Bytes(bytes)),
+                (None, None) => None,
+            };
+            tcp_health_check(&host, port, payload, expectation, read_timeout).await
         }
-        HealthCheckMethod::Port { ip, port } => port_health_check(&ip, port).await,
         HealthCheckMethod::Shell {
             command,
             status,
             regex,
-        } => shell_health_check(&command, status, regex).await,
+        } => shell_health_check(&command, status, regex).await.into(),
+        HealthCheckMethod::Ping {
+            ip,
+            count,
+            packet_timeout,
+            max_loss,
+        } => ping_health_check(&ip, count, packet_timeout, max_loss)
+            .await
+            .into(),
+        HealthCheckMethod::Dns {
+            server,
+            port,
+            name,
+            record_type,
+            protocol,
+            rcode,
+            addresses,
+        } => dns_health_check(
+            &server,
+            port,
+            &name,
+            &record_type,
+            protocol,
+            &rcode,
+            &addresses,
+        )
+        .await
+        .into(),
+        HealthCheckMethod::Nfs { ip, port, export } => {
+            nfs_health_check(&ip, port, export.as_deref()).await.into()
+        }
+        HealthCheckMethod::Smb {
+            ip,
+            port,
+            min_dialect,
+        } => smb_health_check(&ip, port, min_dialect.as_deref())
+            .await
+            .into(),
+        HealthCheckMethod::Ssh {
+            host,
+            port,
+            command,
+            user,
+            identity,
+            passphrase,
+            known_hosts,
+            status,
+            regex,
+        } => {
+            let passphrase = match passphrase.as_ref().map(Secret::read).transpose() {
+                Ok(passphrase) => passphrase,
+                Err(e) => return CheckResult::failed(e.to_string()),
+            };
+            // Validation makes sure commands come with a user and identity
+            let command = match (command.as_deref(), user.as_deref(), identity.as_deref()) {
+                (Some(command), Some(user), Some(identity)) => Some(SshCommand {
+                    command,
+                    known_hosts: known_hosts.as_deref(),
+                    login: ssh::Login {
+                        user,
+                        identity: std::path::Path::new(identity),
+                        passphrase: passphrase.as_deref(),
+                    },
+                    status,
+                    regex: regex.as_ref(),
+                }),
+                _ => None,
+            };
+            ssh_health_check(&host, port, command).await
+        }
+        HealthCheckMethod::Tls {
+            host,
+            port,
+            server_name,
+            insecure_skip_verify,
+            subject,
+            issuer,
+            min_days_valid,
+            fingerprint,
+        } => {
+            let target = tls::Target {
+                host: &host,
+                port,
+                server_name: server_name.as_deref().unwrap_or(&host),
+                insecure_skip_verify,
+            };
+            let expected = tls::Expectations {
+                subject: subject.as_deref(),
+                issuer: issuer.as_deref(),
+                min_days_valid,
+                fingerprint: fingerprint.as_deref(),
+            };
+            tls_health_check(&target, &expected).await
+        }
+        HealthCheckMethod::Plugin { plugin, params } => plugin_health_check(&plugin, &params).await,
+    }
+}
+
+// How long a single check may take in `check_already_up` when it has no attempt timeout. A server
+// that is down often just doesn't answer, and waiting for that is time it could spend booting.
+const ALREADY_UP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Runs every check once before the WOL packet is sent, so that servers that are already online
+// are not woken up (and waited on) again. Servers without any checks are never considered up.
+pub async fn check_already_up(servers: Arc<RwLock<Vec<Server>>>, index: usize) -> bool {
+    let checks = {
+        let servers_read = servers.read().await;
+        servers_read[index].check.clone()
+    };
+
+    if checks.is_empty() {
+        return false;
     }
+
+    for check in checks {
+        if !check.attempt_once().await.passed {
+            return false;
+        }
+    }
+
+    {
+        let mut servers_write = servers.write().await;
+        let server = &mut servers_write[index];
+        server.status = ServerStatus::AlreadyUp;
+        for check in server.check.iter_mut() {
+            check.status = CheckStatus::Ok;
+        }
+    }
+    true
 }
 
 pub async fn perform_health_checks(
     servers: Arc<RwLock<Vec<Server>>>,
     index: usize,
+) -> ServerStatus {
+    run_checks(servers, index, ServerStatus::Ok).await
+}
+
+// Same as `perform_health_checks`, but for servers prepared by `shutdown_plan`
+pub async fn perform_down_checks(servers: Arc<RwLock<Vec<Server>>>, index: usize) -> ServerStatus {
+    run_checks(servers, index, ServerStatus::Down).await
+}
+
+async fn run_checks(
+    servers: Arc<RwLock<Vec<Server>>>,
+    index: usize,
+    success: ServerStatus,
 ) -> ServerStatus {
     let mut tasks = Vec::new();
 
@@ -352,20 +1554,28 @@ pub async fn perform_health_checks(
 
         let servers_clone = servers.clone();
         tasks.push(tokio::spawn(async move {
-            let start_time = Instant::now();
-            loop {
-                if start_time.elapsed() >= check.timeout {
+            // The timeout is a hard deadline, an attempt that is still running when it passes
+            // is cancelled (and dropped) rather than waited on
+            let deadline = tokio::time::Instant::now() + check.timeout;
+            let attempts = async {
+                loop {
+                    let result = check.attempt().await;
                     {
                         let mut servers_write = servers_clone.write().await;
-                        servers_write[index].check[check_index].status = CheckStatus::TimedOut;
+                        servers_write[index].check[check_index].message = result.message;
+                    }
+                    if result.passed != check.inverted {
+                        break;
                     }
-                    return CheckStatus::TimedOut;
-                }
-                if check_health(check.method.clone()).await {
-                    break;
-                } else {
                     tokio::time::sleep(check.retry).await;
                 }
+            };
+            if tokio::time::timeout_at(deadline, attempts).await.is_err() {
+                {
+                    let mut servers_write = servers_clone.write().await;
+                    servers_write[index].check[check_index].status = CheckStatus::TimedOut;
+                }
+                return CheckStatus::TimedOut;
             }
             {
                 let mut servers_write = servers_clone.write().await;
@@ -386,20 +1596,23 @@ pub async fn perform_health_checks(
         servers_write[index].status = if timeout {
             ServerStatus::TimedOut
         } else {
-            ServerStatus::Ok
+            success
         };
     }
 
     if timeout {
         ServerStatus::TimedOut
     } else {
-        ServerStatus::Ok
+        success
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::power::PlugPowerOn;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_circular_dependencies() {
@@ -522,63 +1735,668 @@ mod tests {
             retry: 2 minutes
         "#;
 
-        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
-        let result = validate_health_check(&server.check[0].method);
-        assert!(matches!(
-            result,
-            Err(ServerConfigError::BadHealthCheckDefinition(_))
-        ));
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let result = validate_health_check(&server.check[0].method);
+        assert!(matches!(
+            result,
+            Err(ServerConfigError::BadHealthCheckDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_port_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        vlan: 100
+        depends: []
+        check:
+          - type: port
+            ip: "invalid_ip"   # Neither an IP address nor a hostname
+            port: 80
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let result = validate_health_check(&server.check[0].method);
+
+        assert!(matches!(
+            result,
+            Err(ServerConfigError::BadHealthCheckDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_ping_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: ping
+            ip: "firewall.lan"   # Needs to be an IP address
+          - type: ping
+            ip: "192.168.1.1"
+            count: 0
+          - type: ping
+            ip: "192.168.1.1"
+            max_loss: 101
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_dns_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: dns
+            name: "nas.lan"
+            server: "resolver.lan"  # Needs to be an IP address
+          - type: dns
+            server: "192.168.1.1"
+            name: "nas.lan"
+            record_type: "BOGUS"
+          - type: dns
+            server: "192.168.1.1"
+            name: "nas.lan"
+            rcode: "NOTOK"
+          - type: dns
+            server: "192.168.1.1"
+            name: "nas.lan"
+            record_type: "MX"
+            addresses: ["192.168.1.10"]
+          - type: dns
+            server: "192.168.1.1"
+            name: "nas..lan"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_nfs_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: nfs
+            ip: "nas.lan"
+          - type: nfs
+            ip: "192.168.1.10"
+            export: "srv/media"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_smb_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: smb
+            ip: "nas.lan"
+          - type: smb
+            ip: "192.168.1.10"
+            min_dialect: "1.0"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_missing_plugin() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: plugin
+            plugin: "/nonexistent/rallyup-plugin"
+            params:
+              vmid: 100
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        assert!(matches!(
+            validate_health_check(&server.check[0].method),
+            Err(ServerConfigError::BadHealthCheckDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_http_request() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: http
+            url: "http://example.com"
+            status: 200
+            method: "NOT A METHOD"
+          - type: http
+            url: "https://example.com"
+            status: 200
+            ca_file: "/nonexistent/ca.pem"
+          - type: http
+            url: "http://example.com"
+            json:
+              - path: "nodes[first].status"
+                equals: online
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_port_conversation() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: port
+            host: "redis.lan"
+            port: 6379
+            send: "PING\r\n"
+            send_hex: "50494e470d0a"
+          - type: port
+            host: "redis.lan"
+            port: 6379
+            expect: "PONG"
+            expect_hex: "504f4e47"
+          - type: port
+            host: "redis.lan"
+            port: 6379
+            send_hex: "not hex"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_truncate_command() {
+        assert_eq!(truncate_command("uptime", 30), "uptime");
+        let command = "echo ".to_string() + &"é".repeat(40);
+        let truncated = truncate_command(&command, 30);
+        assert!(truncated.starts_with(&("echo ".to_string() + &"é".repeat(22))));
+        assert!(!truncated.starts_with(&("echo ".to_string() + &"é".repeat(23))));
+    }
+
+    #[test]
+    fn test_invalid_ssh_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: ssh
+            host: "nas.lan"
+            status: 0           # Nothing to match without a command
+          - type: ssh
+            host: "nas.lan"
+            command: "uptime"   # Needs a user and identity
+            status: 0
+          - type: ssh
+            host: "nas.lan"
+            user: "rallyup"
+            identity: "/etc/rallyup/id_ed25519"
+            command: "uptime"   # Nothing to match
+          - type: ssh
+            host: "nas.lan"
+            user: "rallyup"
+            identity: "/etc/rallyup/id_ed25519"
+            passphrase:
+              env: RALLYUP_TEST_UNSET_PASSPHRASE
+            command: "uptime"
+            status: 0
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_tls_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        check:
+          - type: tls
+            host: ""
+          - type: tls
+            host: "nas.lan"
+            fingerprint: "not a fingerprint"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            assert!(matches!(
+                validate_health_check(&healthcheck.method),
+                Err(ServerConfigError::BadHealthCheckDefinition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_valid_health_checks() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        vlan: 100
+        depends: []
+        check:
+          - type: http
+            url: "http://example.com"
+            status: 200          # Valid: status is provided
+            regex: ~
+          - type: http
+            url: "https://pve.lan:8006/api2/json/version"
+            status: 200
+            method: POST
+            headers:
+              Accept: application/json
+            body: "{}"
+            auth:
+              type: bearer
+              token: "PVEAPIToken=root@pam!rallyup=secret"
+            insecure_skip_verify: true
+            max_redirects: 0
+          - type: http
+            url: "http://192.168.1.5:8080/health"
+            json:
+              - path: status
+                equals: healthy
+              - path: "cluster.nodes[0].load"
+                less_than: 2.5
+          - type: port
+            ip: "192.168.1.1"    # Valid IP
+            port: 80
+          - type: port
+            host: "redis.lan"    # Valid hostname
+            port: 6379
+            send: "PING\r\n"
+            expect: "^\\+PONG"
+            read_timeout: 2s
+          - type: port
+            host: "192.168.1.20"
+            port: 3551
+            send_hex: "0006 7374 6174 7573"
+            expect_hex: "5354415455532020203a204f4e4c494e45"
+          - type: shell
+            command: "echo Hello"
+            status: ~            # Valid: regex is provided
+            regex: "Hello"
+          - type: ping
+            ip: "192.168.1.1"
+            count: 3
+            packet_timeout: 500ms
+            max_loss: 33
+          - type: dns
+            server: "192.168.1.1"
+            name: "nas.lan"
+            addresses: ["192.168.1.10"]
+          - type: dns
+            server: "192.168.1.1"
+            name: "_ldap._tcp.lan"
+            record_type: srv
+            protocol: tcp
+            rcode: NXDOMAIN
+          - type: nfs
+            ip: "192.168.1.10"
+          - type: nfs
+            ip: "192.168.1.10"
+            export: "/srv/media"
+          - type: smb
+            ip: "192.168.1.10"
+          - type: smb
+            ip: "192.168.1.10"
+            port: 1445
+            min_dialect: "3.0"
+          - type: ssh
+            host: "nas.lan"
+          - type: ssh
+            host: "192.168.1.10"
+            port: 2222
+            user: "rallyup"
+            identity: "/etc/rallyup/id_ed25519"
+            command: "zpool status -x"
+            status: 0
+            regex: "all pools are healthy"
+          - type: tls
+            host: "nas.lan"
+            subject: "nas.lan"
+            issuer: "O=Let's Encrypt"
+            min_days_valid: 14
+          - type: tls
+            host: "192.168.1.10"
+            port: 8443
+            server_name: "nas.lan"
+            insecure_skip_verify: true
+            fingerprint: "00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff:00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        for healthcheck in &server.check {
+            let result = validate_health_check(&healthcheck.method);
+            assert!(result.is_ok())
+        }
+    }
+
+    #[test]
+    fn test_wol_transports() {
+        let yaml_data = r#"
+        - name: "raw"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "broadcast"
+          mac: "11:22:33:44:55:66"
+          transport:
+            type: udp
+
+        - name: "directed"
+          mac: "22:33:44:55:66:77"
+          password: "01:23:45:67:89:AB"
+          transport:
+            type: udp
+            address: 192.168.100.255
+            port: 7
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert!(servers[0].transport.is_none());
+        assert!(matches!(
+            servers[0].power_on_method(),
+            PowerOn::Wol(WolPowerOn {
+                transport: WolTransport::Ethernet,
+                ..
+            })
+        ));
+        assert!(matches!(
+            servers[1].transport,
+            Some(WolTransport::Udp { address, port: 9 }) if address == IpAddr::V4(Ipv4Addr::BROADCAST)
+        ));
+        assert!(matches!(
+            servers[2].transport,
+            Some(WolTransport::Udp { address, port: 7 }) if address.to_string() == "192.168.100.255"
+        ));
+        for server in &servers {
+            assert!(validate_power_on(server).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_invalid_wol_transports() {
+        let yaml_data = r#"
+        - name: "missing_interface"
+          mac: "00:11:22:33:44:55"
+
+        - name: "udp_with_vlan"
+          mac: "11:22:33:44:55:66"
+          vlan: 100
+          transport:
+            type: udp
+
+        - name: "short_password"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+          password: "01:23:45"
+
+        - name: "empty_burst"
+          mac: "33:44:55:66:77:88"
+          interface: "eth0"
+          resend:
+            count: 3
+            burst: 0
+
+        - name: "no_resend_interval"
+          mac: "44:55:66:77:88:99"
+          interface: "eth0"
+          resend:
+            interval: 0s
+            count: 3
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        for server in &servers {
+            assert!(matches!(
+                validate_power_on(server),
+                Err(ServerConfigError::BadWakeDefinition(_))
+            ));
+        }
     }
 
     #[test]
-    fn test_invalid_port_check() {
+    fn test_power_on_methods() {
         let yaml_data = r#"
-        name: "server1"
-        mac: "00:11:22:33:44:55"
-        interface: "eth0"
-        vlan: 100
-        depends: []
-        check:
-          - type: port
-            ip: "invalid_ip"   # Invalid IP address
-            port: 80
+        - name: "legacy"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "wol"
+          power_on:
+            method: wol
+            mac: "11:22:33:44:55:66"
+            transport:
+              type: udp
+
+        - name: "modem"
+          power_on:
+            method: shell
+            command: "pdu-ctl outlet 3 on"
+
+        - name: "bmc"
+          power_on:
+            method: http
+            url: "https://192.168.1.20/power/on"
+            http_method: put
+            auth:
+              type: basic
+              username: admin
+
+        - name: "printer"
+          power_on:
+            method: plug
+            url: "http://192.168.1.50"
+            kind: tasmota
+          resend:
+            count: 2
         "#;
 
-        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
-        let result = validate_health_check(&server.check[0].method);
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
 
         assert!(matches!(
-            result,
-            Err(ServerConfigError::BadHealthCheckDefinition(_))
+            servers[0].power_on_method(),
+            PowerOn::Wol(WolPowerOn { mac, interface: Some(_), .. }) if mac == "00:11:22:33:44:55"
+        ));
+        assert!(matches!(
+            servers[1].power_on_method(),
+            PowerOn::Wol(WolPowerOn {
+                transport: WolTransport::Udp { .. },
+                ..
+            })
+        ));
+        assert!(matches!(servers[2].power_on_method(), PowerOn::Shell(_)));
+        assert!(matches!(
+            servers[3].power_on_method(),
+            PowerOn::Http(http) if http.request().method == "put"
+        ));
+        assert!(matches!(
+            servers[4].power_on_method(),
+            PowerOn::Plug(PlugPowerOn {
+                relay: None,
+                cycle: None,
+                ..
+            })
         ));
+        for server in &servers {
+            assert!(validate_power_on(server).is_ok(), "{}", server.name);
+        }
     }
 
     #[test]
-    fn test_valid_health_checks() {
+    fn test_invalid_power_on_methods() {
         let yaml_data = r#"
-        name: "server1"
-        mac: "00:11:22:33:44:55"
-        interface: "eth0"
-        vlan: 100
-        depends: []
-        check:
-          - type: http
-            url: "http://example.com"
-            status: 200          # Valid: status is provided
-            regex: ~
-          - type: port
-            ip: "192.168.1.1"    # Valid IP
-            port: 80
-          - type: shell
-            command: "echo Hello"
-            status: ~            # Valid: regex is provided
-            regex: "Hello"
+        - name: "nothing"
+          interface: "eth0"
+
+        - name: "both"
+          mac: "00:11:22:33:44:55"
+          power_on:
+            method: shell
+            command: "true"
+
+        - name: "transport_with_method"
+          transport:
+            type: udp
+          power_on:
+            method: wol
+            mac: "00:11:22:33:44:55"
+            transport:
+              type: udp
+
+        - name: "wol_without_interface"
+          power_on:
+            method: wol
+            mac: "11:22:33:44:55:66"
+
+        - name: "resent_shell"
+          power_on:
+            method: shell
+            command: "pdu-ctl outlet 3 toggle"
+          resend:
+            count: 3
+
+        - name: "bad_http_method"
+          power_on:
+            method: http
+            url: "http://192.168.1.20/power/on"
+            http_method: "NOT A METHOD"
+
+        - name: "bad_redfish_url"
+          power_on:
+            method: redfish
+            url: "192.168.1.20"
+            username: root
+            password:
+              env: BMC_PASSWORD
+
+        - name: "ipmi_without_authentication"
+          power_on:
+            method: ipmi
+            address: 192.168.1.21
+            username: admin
+            password:
+              env: BMC_PASSWORD
+            cipher_suite: 0
+
+        - name: "proxmox_without_node"
+          power_on:
+            method: proxmox
+            url: "https://192.168.1.30:8006"
+            token:
+              env: PVE_TOKEN
+            node: ""
+            vmid: 101
+
+        - name: "snmp_bad_oid"
+          power_on:
+            method: snmp
+            address: 192.168.1.40
+            community:
+              env: PDU_COMMUNITY
+            oid: "1.3.6.1.4.1.318.x"
+            on_value: 1
+
+        - name: "snmp_cycle_without_off_value"
+          power_on:
+            method: snmp
+            address: 192.168.1.40
+            community:
+              env: PDU_COMMUNITY
+            oid: ".1.3.6.1.4.1.318.1.1.4.4.2.1.3.5"
+            on_value: 1
+            cycle: 10s
+
+        - name: "resent_plug_cycle"
+          power_on:
+            method: plug
+            url: "http://192.168.1.50"
+            kind: tasmota
+            cycle: 10s
+          resend:
+            count: 3
         "#;
 
-        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
-        for healthcheck in &server.check {
-            let result = validate_health_check(&healthcheck.method);
-            assert!(result.is_ok())
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        for server in &servers {
+            assert!(
+                matches!(
+                    validate_power_on(server),
+                    Err(ServerConfigError::BadWakeDefinition(_))
+                ),
+                "{}",
+                server.name
+            );
         }
     }
 
@@ -621,6 +2439,89 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_optional_dependencies() {
+        let yaml_data = r#"
+        name: "hypervisor"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        depends:
+          - "storage"
+          - name: "backup"
+            optional: true
+          - name: "firewall"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        assert_eq!(
+            server.depends,
+            vec![
+                Dependency {
+                    name: "storage".into(),
+                    optional: false
+                },
+                Dependency {
+                    name: "backup".into(),
+                    optional: true
+                },
+                Dependency {
+                    name: "firewall".into(),
+                    optional: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_servers() {
+        let yaml_data = r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "firewall"
+          mac: "11:22:33:44:55:66"
+          interface: "eth0"
+          depends:
+            - "switch"
+
+        - name: "switch"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+
+        - name: "hypervisor"
+          mac: "33:44:55:66:77:88"
+          interface: "eth0"
+          depends:
+            - "storage"
+            - "firewall"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        let names = |targets: &[&str]| {
+            let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+            select_servers(&servers, &targets)
+                .expect("Failed to select servers")
+                .into_iter()
+                .map(|s| s.name)
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(names(&["firewall"]), vec!["switch", "firewall"]);
+        assert_eq!(names(&["storage", "switch"]), vec!["storage", "switch"]);
+        assert_eq!(
+            names(&["hypervisor"]),
+            vec!["storage", "switch", "firewall", "hypervisor"]
+        );
+
+        assert!(matches!(
+            select_servers(&servers, &["nas".to_string()]),
+            Err(ServerConfigError::UnknownServer(name)) if name == "nas"
+        ));
+    }
+
     #[tokio::test]
     async fn test_http_health_check_success() {
         let mut server = mockito::Server::new_async().await;
@@ -637,22 +2538,22 @@ mod tests {
         let status = Some(200);
         let regex = Some(Regex::new("health").unwrap());
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(result.passed);
 
         // Just status
         let status = Some(200);
         let regex = None;
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(result.passed);
 
         // Just regex
         let status = None;
         let regex = Some(Regex::new("health").unwrap());
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(result.passed);
     }
 
     #[tokio::test]
@@ -672,25 +2573,69 @@ mod tests {
         let status = Some(200);
         let regex = Some(Regex::new("health").unwrap());
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(!result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(!result.passed);
 
         // Just status
         let status = Some(200);
         let regex = None;
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(!result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(!result.passed);
 
         // Just regex
         let status = None;
         let regex = Some(Regex::new("health").unwrap());
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(!result);
+        let result = http_health_check(&url, &HttpRequest::default(), status, regex, &[]).await;
+        assert!(!result.passed);
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_json() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/health")
+            .with_status(200)
+            .with_body(r#"{"status":"healthy","cluster":{"quorate":0}}"#)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/text")
+            .with_status(200)
+            .with_body("healthy")
+            .create_async()
+            .await;
+
+        let json: Vec<JsonAssertion> = serde_yaml_ng::from_str(
+            r#"
+            - path: status
+              equals: healthy
+            - path: cluster.quorate
+              equals: 1
+            "#,
+        )
+        .expect("Failed to parse YAML");
+
+        let url = format!("{}/health", server.url());
+        let result = http_health_check(&url, &HttpRequest::default(), None, None, &json).await;
+        assert!(!result.passed);
+        assert_eq!(
+            result.message.unwrap(),
+            "cluster.quorate: expected 1, got 0"
+        );
+
+        let result = http_health_check(&url, &HttpRequest::default(), None, None, &json[..1]).await;
+        assert!(result.passed);
+
+        let url = format!("{}/text", server.url());
+        let result = http_health_check(&url, &HttpRequest::default(), None, None, &json).await;
+        assert!(!result.passed);
+        assert!(result.message.unwrap().starts_with("response is not JSON"));
     }
 
     #[tokio::test]
+    #[allow(clippy::needless_borrow)]
     async fn test_port_health_check_success() {
         // Set up a mock TCP listener on an available port
         let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
@@ -708,6 +2653,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::needless_borrow)]
     async fn test_port_health_check_fail() {
         // Set up a mock TCP listener on an available port
         // Could probably just pick a random port, but I want to make sure
@@ -725,6 +2671,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::needless_borrow)]
     async fn test_shell_health_check_success() {
         let command = "echo 'hello'";
 
@@ -751,6 +2698,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::needless_borrow)]
     async fn test_shell_health_check_fail() {
         let command = "echo 'hello'";
 
@@ -812,4 +2760,241 @@ mod tests {
         // Also check that the number of retries is correct
         mock.assert();
     }
+
+    #[tokio::test]
+    async fn test_shutdown_plan() {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let yaml_data = r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          shutdown:
+            type: shell
+            command: "true"
+          down:
+            - type: port
+              ip: "127.0.0.1"
+              port: <port>
+              timeout: 2s
+
+        - name: "hypervisor"
+          mac: "11:22:33:44:55:66"
+          interface: "eth0"
+          depends:
+            - "storage"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+          shutdown:
+            type: ssh
+            host: "192.168.1.20"
+            user: root
+            command: "shutdown -h now"
+          down:
+            - type: port
+              ip: "127.0.0.1"
+              port: <port>
+              timeout: 2s
+        "#;
+        let yaml_data = yaml_data.replace("<port>", &port.to_string());
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(&yaml_data).expect("Failed to parse YAML");
+        let wake_order = determine_wakeup_order(&servers).unwrap();
+
+        let plan = shutdown_plan(wake_order).unwrap();
+        assert_eq!(
+            plan.iter().map(|s| s.name.as_str()).collect::<Vec<&str>>(),
+            vec!["hypervisor", "storage"]
+        );
+        // The down checks replace the regular checks
+        assert_eq!(plan[0].check.len(), 1);
+        assert!(plan[0].check[0].inverted);
+        assert_eq!(plan[1].check.len(), 1);
+
+        // Nothing is listening on the port, so the hypervisor counts as down
+        let server_state = Arc::new(RwLock::new(plan));
+        let result = perform_down_checks(server_state.clone(), 0).await;
+        assert_eq!(result, ServerStatus::Down);
+    }
+
+    #[test]
+    fn test_shutdown_plan_missing_action() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert!(matches!(
+            shutdown_plan(servers),
+            Err(ServerConfigError::BadShutdownDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_shutdown_plan_missing_down_checks() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          shutdown:
+            type: shell
+            command: "true"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert!(matches!(
+            shutdown_plan(servers),
+            Err(ServerConfigError::BadShutdownDefinition(e)) if e.contains("down checks")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_already_up() {
+        let yaml_data = r#"
+        - name: "up"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+            - type: shell
+              command: "echo hello"
+              regex: "hello"
+
+        - name: "partially_up"
+          mac: "11:22:33:44:55:66"
+          interface: "eth0"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+            - type: shell
+              command: "false"
+              status: 0
+
+        - name: "no_checks"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let server_state = Arc::new(RwLock::new(servers));
+
+        assert!(check_already_up(server_state.clone(), 0).await);
+        assert!(!check_already_up(server_state.clone(), 1).await);
+        assert!(!check_already_up(server_state.clone(), 2).await);
+
+        let servers = server_state.read().await;
+        assert_eq!(servers[0].status, ServerStatus::AlreadyUp);
+        assert!(matches!(servers[0].check[1].status, CheckStatus::Ok));
+        assert_eq!(servers[1].status, ServerStatus::Waiting);
+        assert_eq!(servers[2].status, ServerStatus::Waiting);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_already_up_timeout() {
+        // Accepts connections, but never answers
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let yaml_data = r#"
+        - name: "hung"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          check:
+            - type: port
+              host: "127.0.0.1"
+              port: <port>
+              send: "PING"
+              expect: "PONG"
+              read_timeout: 1h
+              timeout: 5m
+        "#;
+        let yaml_data = yaml_data.replace("<port>", &port.to_string());
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(&yaml_data).expect("Failed to parse YAML");
+        let server_state = Arc::new(RwLock::new(servers));
+
+        // The pre-check gives up long before the check's own timeout
+        let start_time = tokio::time::Instant::now();
+        assert!(!check_already_up(server_state, 0).await);
+        assert!(start_time.elapsed() <= ALREADY_UP_TIMEOUT);
+        drop(listener);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_on_timeout() {
+        let yaml_data = r#"
+        - name: "stuck"
+          power_on:
+            method: shell
+            command: "sleep 600"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        assert!(matches!(
+            servers[0].power_on().await,
+            Err(PowerError::TimedOut(timeout)) if timeout == Duration::from_secs(60)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_attempt_timeout() {
+        let marker = std::env::temp_dir().join(format!("rallyup-attempt-{}", std::process::id()));
+        let _ = fs::remove_file(&marker);
+
+        // Each attempt hangs for longer than the attempt timeout, and the overall timeout
+        // cuts off the last attempt halfway through
+        let yaml_data = r#"
+        - name: "hung_server"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          check:
+            - type: shell
+              command: "sleep 1; touch <marker>"
+              status: 0
+              retry: 50ms
+              attempt_timeout: 200ms
+              timeout: 500ms
+        "#;
+        let yaml_data = yaml_data.replace("<marker>", &marker.to_string_lossy());
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(&yaml_data).expect("Failed to parse YAML");
+        assert_eq!(
+            servers[0].check[0].attempt_timeout,
+            Some(std::time::Duration::from_millis(200))
+        );
+
+        let server_state = Arc::new(RwLock::new(servers));
+
+        let start_time = Instant::now();
+        let result = perform_health_checks(server_state.clone(), 0).await;
+        assert_eq!(result, ServerStatus::TimedOut);
+        assert!(start_time.elapsed() < std::time::Duration::from_millis(900));
+
+        // The shells from the cancelled attempts should have been killed before they got
+        // to the end of the command
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(!marker.exists());
+    }
 }
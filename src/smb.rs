@@ -0,0 +1,268 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const PROTOCOL_ID: &[u8] = b"\xFESMB";
+const SIZE_HEADER: usize = 64;
+const COMMAND_NEGOTIATE: u16 = 0;
+const FLAG_SERVER_TO_REDIR: u32 = 0x0000_0001;
+const SECURITY_MODE_SIGNING_ENABLED: u16 = 0x0001;
+const CONTEXT_PREAUTH_INTEGRITY: u16 = 0x0001;
+const HASH_ALGORITHM_SHA512: u16 = 0x0001;
+const SIZE_SALT: usize = 32;
+// For connecting, and then for the NEGOTIATE exchange
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DIALECTS: &[(&str, u16)] = &[
+    ("2.0.2", 0x0202),
+    ("2.1", 0x0210),
+    ("3.0", 0x0300),
+    ("3.0.2", 0x0302),
+    ("3.1.1", 0x0311),
+];
+
+pub fn dialect_from_str(dialect: &str) -> Option<u16> {
+    DIALECTS
+        .iter()
+        .find(|(name, _)| *name == dialect)
+        .map(|(_, value)| *value)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message.to_string())
+}
+
+// SMB2 Header Layout (MS-SMB2 2.2.1), all fields are little endian:
+// -------------------------------------------------------------------------------------------
+// | Protocol ID | Structure Size | Credit Charge | Status  | Command | Credits | Flags   | ... |
+// -------------------------------------------------------------------------------------------
+// | 4 bytes     | 2 bytes        | 2 bytes       | 4 bytes | 2 bytes | 2 bytes | 4 bytes | ... |
+// -------------------------------------------------------------------------------------------
+// followed by the next command offset, message ID, process ID, tree ID, session ID and signature,
+// 64 bytes in total. Over TCP, every message is preceded by a 4 byte big endian length.
+//
+// The NEGOTIATE request lists the dialects we speak, and since we offer 3.1.1 it also needs
+// a preauth integrity negotiate context, 8 byte aligned after the dialects. The response
+// has the dialect the server picked 4 bytes into its body.
+
+fn create_header(command: u16) -> Vec<u8> {
+    let mut header = vec![0u8; SIZE_HEADER];
+    header[0..4].copy_from_slice(PROTOCOL_ID);
+    header[4..6].copy_from_slice(&(SIZE_HEADER as u16).to_le_bytes());
+    header[12..14].copy_from_slice(&command.to_le_bytes());
+    // Credits requested
+    header[14..16].copy_from_slice(&1u16.to_le_bytes());
+    header
+}
+
+fn create_negotiate_request(salt: &[u8; SIZE_SALT]) -> Vec<u8> {
+    let mut request = create_header(COMMAND_NEGOTIATE);
+
+    let dialects_end = SIZE_HEADER + 36 + DIALECTS.len() * 2;
+    let context_offset = dialects_end.next_multiple_of(8);
+
+    request.extend_from_slice(&36u16.to_le_bytes());
+    request.extend_from_slice(&(DIALECTS.len() as u16).to_le_bytes());
+    request.extend_from_slice(&SECURITY_MODE_SIGNING_ENABLED.to_le_bytes());
+    // Reserved, capabilities and client GUID
+    request.extend_from_slice(&[0u8; 2 + 4 + 16]);
+    request.extend_from_slice(&(context_offset as u32).to_le_bytes());
+    // Negotiate context count, and reserved
+    request.extend_from_slice(&1u16.to_le_bytes());
+    request.extend_from_slice(&[0u8; 2]);
+    for (_, dialect) in DIALECTS {
+        request.extend_from_slice(&dialect.to_le_bytes());
+    }
+    request.resize(context_offset, 0);
+
+    request.extend_from_slice(&CONTEXT_PREAUTH_INTEGRITY.to_le_bytes());
+    request.extend_from_slice(&((6 + SIZE_SALT) as u16).to_le_bytes());
+    request.extend_from_slice(&[0u8; 4]);
+    request.extend_from_slice(&1u16.to_le_bytes());
+    request.extend_from_slice(&(SIZE_SALT as u16).to_le_bytes());
+    request.extend_from_slice(&HASH_ALGORITHM_SHA512.to_le_bytes());
+    request.extend_from_slice(salt);
+    request
+}
+
+fn read_u16(message: &[u8], offset: usize) -> io::Result<u16> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid_data("truncated SMB2 message"))
+}
+
+fn read_u32(message: &[u8], offset: usize) -> io::Result<u32> {
+    message
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid_data("truncated SMB2 message"))
+}
+
+// Returns the dialect the server picked
+fn parse_negotiate_response(message: &[u8]) -> io::Result<u16> {
+    // SMB1-only servers answer with a \xFFSMB header instead
+    if message.get(0..4) != Some(PROTOCOL_ID) {
+        return Err(invalid_data("not an SMB2 message"));
+    }
+    if read_u16(message, 12)? != COMMAND_NEGOTIATE
+        || read_u32(message, 16)? & FLAG_SERVER_TO_REDIR == 0
+    {
+        return Err(invalid_data("not an SMB2 NEGOTIATE response"));
+    }
+
+    let status = read_u32(message, 8)?;
+    if status != 0 {
+        return Err(invalid_data(&format!(
+            "SMB2 NEGOTIATE failed with status {:#010x}",
+            status
+        )));
+    }
+
+    if read_u16(message, SIZE_HEADER)? != 65 {
+        return Err(invalid_data("malformed SMB2 NEGOTIATE response"));
+    }
+    let dialect = read_u16(message, SIZE_HEADER + 4)?;
+    if !DIALECTS.iter().any(|(_, value)| *value == dialect) {
+        return Err(invalid_data(&format!(
+            "server picked a dialect that was not offered: {:#06x}",
+            dialect
+        )));
+    }
+    Ok(dialect)
+}
+
+pub async fn negotiate(address: SocketAddr) -> io::Result<u16> {
+    let mut salt = [0u8; SIZE_SALT];
+    let nanos = std::time::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .as_nanos();
+    salt[..16].copy_from_slice(&nanos.to_le_bytes());
+    let negotiate = create_negotiate_request(&salt);
+
+    let mut stream = timeout(TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| timed_out("timed out connecting to the SMB server"))??;
+
+    let exchange = async {
+        let mut request = (negotiate.len() as u32).to_be_bytes().to_vec();
+        request.extend_from_slice(&negotiate);
+        stream.write_all(&request).await?;
+
+        // The first byte of the length is the NetBIOS message type, 0 for session messages
+        let length = stream.read_u32().await? & 0x00FF_FFFF;
+        let mut response = vec![0u8; length as usize];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = timeout(TIMEOUT, exchange)
+        .await
+        .map_err(|_| timed_out("SMB server did not answer the NEGOTIATE request"))??;
+
+    parse_negotiate_response(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn create_negotiate_response(status: u32, dialect: u16) -> Vec<u8> {
+        let mut response = create_header(COMMAND_NEGOTIATE);
+        response[8..12].copy_from_slice(&status.to_le_bytes());
+        response[16..20].copy_from_slice(&FLAG_SERVER_TO_REDIR.to_le_bytes());
+        response.extend_from_slice(&65u16.to_le_bytes());
+        response.extend_from_slice(&SECURITY_MODE_SIGNING_ENABLED.to_le_bytes());
+        response.extend_from_slice(&dialect.to_le_bytes());
+        // The rest of the response body, which we don't look at
+        response.extend_from_slice(&[0u8; 58]);
+        response
+    }
+
+    async fn spawn_smb_responder(response: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let length = stream.read_u32().await.unwrap();
+                let mut request = vec![0u8; length as usize];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request[0..4], PROTOCOL_ID);
+
+                let mut message = (response.len() as u32).to_be_bytes().to_vec();
+                message.extend_from_slice(&response);
+                stream.write_all(&message).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn test_create_negotiate_request() {
+        let request = create_negotiate_request(&[0xAA; SIZE_SALT]);
+        assert_eq!(&request[0..4], PROTOCOL_ID);
+        assert_eq!(read_u16(&request, SIZE_HEADER).unwrap(), 36);
+        assert_eq!(
+            read_u16(&request, SIZE_HEADER + 2).unwrap() as usize,
+            DIALECTS.len()
+        );
+
+        // The negotiate context starts 8 byte aligned, and ends with the salt
+        let context_offset = read_u32(&request, SIZE_HEADER + 28).unwrap() as usize;
+        assert_eq!(context_offset % 8, 0);
+        assert_eq!(
+            read_u16(&request, context_offset).unwrap(),
+            CONTEXT_PREAUTH_INTEGRITY
+        );
+        assert_eq!(request.len(), context_offset + 8 + 6 + SIZE_SALT);
+        assert!(request.ends_with(&[0xAA; SIZE_SALT]));
+    }
+
+    #[test]
+    fn test_parse_negotiate_response() {
+        let response = create_negotiate_response(0, 0x0311);
+        assert_eq!(parse_negotiate_response(&response).unwrap(), 0x0311);
+
+        // STATUS_NOT_SUPPORTED
+        let response = create_negotiate_response(0xC00000BB, 0);
+        assert!(parse_negotiate_response(&response).is_err());
+
+        // The SMB2 wildcard dialect is only used to upgrade from SMB1
+        let response = create_negotiate_response(0, 0x02FF);
+        assert!(parse_negotiate_response(&response).is_err());
+
+        let mut response = create_negotiate_response(0, 0x0300);
+        response[0] = 0xFF;
+        assert!(parse_negotiate_response(&response).is_err());
+
+        assert!(parse_negotiate_response(&response[..SIZE_HEADER]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let address = spawn_smb_responder(create_negotiate_response(0, 0x0302)).await;
+        assert_eq!(negotiate(address).await.unwrap(), 0x0302);
+
+        let address = spawn_smb_responder(create_negotiate_response(0xC0000022, 0)).await;
+        assert!(negotiate(address).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_negotiate_timeout() {
+        // Accepts the connection, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let error = negotiate(listener.local_addr().unwrap()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
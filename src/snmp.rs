@@ -0,0 +1,417 @@
+use crate::udp;
+use serde::Deserialize;
+use thiserror::Error;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const PDU_RESPONSE: u8 = 0xA2;
+const PDU_SET_REQUEST: u8 = 0xA3;
+
+// Index is the error-status of the response PDU (RFC 3416)
+const ERROR_STATUSES: &[&str] = &[
+    "noError",
+    "tooBig",
+    "noSuchName",
+    "badValue",
+    "readOnly",
+    "genErr",
+    "noAccess",
+    "wrongType",
+    "wrongLength",
+    "wrongEncoding",
+    "wrongValue",
+    "noCreation",
+    "inconsistentValue",
+    "resourceUnavailable",
+    "commitFailed",
+    "undoFailed",
+    "authorizationError",
+    "notWritable",
+    "inconsistentName",
+];
+
+#[derive(Debug, Error)]
+pub enum SnmpError {
+    #[error("Failed to reach the SNMP agent: {0}")]
+    ConnectError(#[from] std::io::Error),
+
+    #[error("SNMP agent did not answer, check the address and community")]
+    NoResponse,
+
+    #[error("SNMP agent rejected the SET: {0}")]
+    SetFailed(&'static str),
+}
+
+type Result<T> = std::result::Result<T, SnmpError>;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnmpVersion {
+    V1 = 0,
+    #[default]
+    V2c = 1,
+}
+
+pub struct Agent<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub community: &'a str,
+    pub version: SnmpVersion,
+}
+
+// Dotted OIDs, with or without the leading dot, e.g. .1.3.6.1.4.1.318.1.1.4.4.2.1.3.5
+pub fn parse_oid(oid: &str) -> Option<Vec<u32>> {
+    let arcs: Vec<u32> = oid
+        .strip_prefix('.')
+        .unwrap_or(oid)
+        .split('.')
+        .map(|arc| arc.parse().ok())
+        .collect::<Option<_>>()?;
+    // The first two arcs are encoded together, which only works for these values
+    if arcs.len() < 2 || arcs[0] > 2 || (arcs[0] < 2 && arcs[1] >= 40) {
+        return None;
+    }
+    Some(arcs)
+}
+
+// SNMP messages are BER encoded (X.690): a tag byte, the length of the content (in one
+// byte below 128, otherwise 0x81 or 0x82 followed by one or two length bytes), the content.
+//
+// Message Layout (RFC 1157 for v1, RFC 1901 for v2c):
+// ---------------------------------------------------------------------------------------
+// | SEQUENCE | version | community    | PDU                                              |
+// ---------------------------------------------------------------------------------------
+// | 0x30     | INTEGER | OCTET STRING | request-id, error-status, error-index, varbinds  |
+// ---------------------------------------------------------------------------------------
+// - PDU: tagged 0xA3 for a SetRequest and 0xA2 for the Response, the first three fields are
+//   INTEGERs
+// - varbinds: SEQUENCE of SEQUENCE { OBJECT IDENTIFIER, value }
+
+fn encode_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match content.len() {
+        length @ 0..=0x7F => encoded.push(length as u8),
+        length @ 0x80..=0xFF => encoded.extend([0x81, length as u8]),
+        length => encoded.extend([0x82, (length >> 8) as u8, length as u8]),
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Two's complement in as few bytes as possible, so drop the bytes that only repeat the sign
+    let start = (0..7)
+        .find(|&i| {
+            !((bytes[i] == 0x00 && bytes[i + 1] & 0x80 == 0)
+                || (bytes[i] == 0xFF && bytes[i + 1] & 0x80 != 0))
+        })
+        .unwrap_or(7);
+    encode_tlv(TAG_INTEGER, &bytes[start..])
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let first = u64::from(oid[0]) * 40 + u64::from(oid[1]);
+    let mut content = Vec::new();
+    // Base 128, most significant group first, with the high bit set on all but the last byte
+    for arc in std::iter::once(first).chain(oid[2..].iter().map(|&arc| u64::from(arc))) {
+        let mut groups = vec![(arc & 0x7F) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7F) as u8 | 0x80);
+            rest >>= 7;
+        }
+        content.extend(groups.iter().rev());
+    }
+    encode_tlv(TAG_OID, &content)
+}
+
+fn encode_message(
+    pdu_type: u8,
+    version: SnmpVersion,
+    community: &str,
+    request_id: i32,
+    error_status: u8,
+    oid: &[u32],
+    value: i64,
+) -> Vec<u8> {
+    let varbind = encode_tlv(
+        TAG_SEQUENCE,
+        &[encode_oid(oid), encode_integer(value)].concat(),
+    );
+    let pdu = [
+        encode_integer(request_id.into()),
+        encode_integer(error_status.into()),
+        encode_integer(0),
+        encode_tlv(TAG_SEQUENCE, &varbind),
+    ]
+    .concat();
+    let message = [
+        encode_integer(version as i64),
+        encode_tlv(TAG_OCTET_STRING, community.as_bytes()),
+        encode_tlv(pdu_type, &pdu),
+    ]
+    .concat();
+    encode_tlv(TAG_SEQUENCE, &message)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    // Returns the content of the next element, if it has the expected tag
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let (&found, rest) = self.data.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let length = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            let size = (first & 0x7F) as usize;
+            if size == 0 || size > 2 || rest.len() < size {
+                return None;
+            }
+            let (length, after) = rest.split_at(size);
+            rest = after;
+            length
+                .iter()
+                .fold(0, |length, &byte| length << 8 | byte as usize)
+        };
+        if found != tag || rest.len() < length {
+            return None;
+        }
+        let (content, rest) = rest.split_at(length);
+        self.data = rest;
+        Some(content)
+    }
+
+    fn read_integer(&mut self) -> Option<i64> {
+        let content = self.read(TAG_INTEGER)?;
+        if content.is_empty() || content.len() > 8 {
+            return None;
+        }
+        let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+        Some(
+            content
+                .iter()
+                .fold(sign, |value, &byte| value << 8 | i64::from(byte)),
+        )
+    }
+}
+
+// Returns the request-id and error-status of a Response
+fn decode_response(response: &[u8]) -> Option<(i64, i64)> {
+    let mut message = Reader {
+        data: Reader { data: response }.read(TAG_SEQUENCE)?,
+    };
+    message.read_integer()?;
+    message.read(TAG_OCTET_STRING)?;
+    let mut pdu = Reader {
+        data: message.read(PDU_RESPONSE)?,
+    };
+    Some((pdu.read_integer()?, pdu.read_integer()?))
+}
+
+// Sets an INTEGER object, like the control of a PDU outlet. Agents silently drop requests
+// with the wrong community, and UDP can lose either message, so the request is retried.
+pub async fn set_integer(agent: &Agent<'_>, oid: &[u32], value: i64) -> Result<()> {
+    let socket = udp::connect(agent.host, agent.port).await?;
+    let request_id = (std::time::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .subsec_nanos()
+        & 0x7FFF_FFFF) as i32;
+    let request = encode_message(
+        PDU_SET_REQUEST,
+        agent.version,
+        agent.community,
+        request_id,
+        0,
+        oid,
+        value,
+    );
+
+    udp::exchange(
+        &socket,
+        || Ok(request.clone()),
+        // Late replies to an earlier attempt carry the same request-id, anything else is ignored
+        |reply| match decode_response(reply)? {
+            (id, 0) if id == i64::from(request_id) => Some(Ok(())),
+            (id, status) if id == i64::from(request_id) => {
+                let name = usize::try_from(status)
+                    .ok()
+                    .and_then(|status| ERROR_STATUSES.get(status))
+                    .copied()
+                    .unwrap_or("unknown error");
+                Some(Err(SnmpError::SetFailed(name)))
+            }
+            _ => None,
+        },
+    )
+    .await?
+    .ok_or(SnmpError::NoResponse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UdpSocket;
+
+    // An APC PDU outlet control (sPDUOutletCtl.5)
+    const OUTLET_OID: &str = ".1.3.6.1.4.1.318.1.1.4.4.2.1.3.5";
+    const COMMUNITY: &str = "private";
+
+    struct SetRequest {
+        version: i64,
+        request_id: i64,
+        oid: Vec<u8>,
+        value: i64,
+    }
+
+    fn decode_set_request(request: &[u8]) -> Option<SetRequest> {
+        let mut message = Reader {
+            data: Reader { data: request }.read(TAG_SEQUENCE)?,
+        };
+        let version = message.read_integer()?;
+        if message.read(TAG_OCTET_STRING)? != COMMUNITY.as_bytes() {
+            return None;
+        }
+        let mut pdu = Reader {
+            data: message.read(PDU_SET_REQUEST)?,
+        };
+        let request_id = pdu.read_integer()?;
+        pdu.read_integer()?;
+        pdu.read_integer()?;
+        let mut varbinds = Reader {
+            data: pdu.read(TAG_SEQUENCE)?,
+        };
+        let mut varbind = Reader {
+            data: varbinds.read(TAG_SEQUENCE)?,
+        };
+        Some(SetRequest {
+            version,
+            request_id,
+            oid: varbind.read(TAG_OID)?.to_vec(),
+            value: varbind.read_integer()?,
+        })
+    }
+
+    // Only the outlet OID is writable, and requests with another community are dropped
+    async fn spawn_agent() -> (u16, Arc<Mutex<Vec<SetRequest>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = received.clone();
+
+        tokio::spawn(async move {
+            let outlet = encode_oid(&parse_oid(OUTLET_OID).unwrap());
+            let mut buffer = [0u8; 1500];
+            loop {
+                let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let Some(request) = decode_set_request(&buffer[..length]) else {
+                    continue;
+                };
+                let error_status = if outlet[2..] == request.oid[..] {
+                    0
+                } else {
+                    17
+                };
+                let version = match request.version {
+                    0 => SnmpVersion::V1,
+                    _ => SnmpVersion::V2c,
+                };
+                let response = encode_message(
+                    PDU_RESPONSE,
+                    version,
+                    COMMUNITY,
+                    request.request_id as i32,
+                    error_status,
+                    &[1, 3, 6, 1],
+                    request.value,
+                );
+                socket.send_to(&response, peer).await.unwrap();
+                requests.lock().unwrap().push(request);
+            }
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn test_parse_oid() {
+        assert_eq!(
+            parse_oid("1.3.6.1.2.1.1.5.0").unwrap(),
+            [1, 3, 6, 1, 2, 1, 1, 5, 0]
+        );
+        assert_eq!(parse_oid(".1.3.6").unwrap(), [1, 3, 6]);
+        for invalid in ["", "1", "1.3.", "1..3", "3.1", "1.40", "1.3.x"] {
+            assert!(
+                parse_oid(invalid).is_none(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode_oid(&parse_oid("1.3.6.1.2.1.1.5.0").unwrap()),
+            [0x06, 0x08, 0x2B, 0x06, 0x01, 0x02, 0x01, 0x01, 0x05, 0x00]
+        );
+        assert_eq!(
+            encode_oid(&[1, 3, 6, 1, 4, 1, 318]),
+            [0x06, 0x07, 0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x3E]
+        );
+        assert_eq!(encode_integer(0), [0x02, 0x01, 0x00]);
+        assert_eq!(encode_integer(128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(encode_integer(-1), [0x02, 0x01, 0xFF]);
+        assert_eq!(encode_integer(-129), [0x02, 0x02, 0xFF, 0x7F]);
+        assert_eq!(
+            encode_tlv(TAG_OCTET_STRING, &[0; 200])[..3],
+            [0x04, 0x81, 200]
+        );
+
+        for value in [0, 1, 127, 128, -128, -129, 65535, i64::from(i32::MIN)] {
+            let mut reader = Reader {
+                data: &encode_integer(value),
+            };
+            assert_eq!(reader.read_integer(), Some(value));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_integer() {
+        let (port, requests) = spawn_agent().await;
+        let oid = parse_oid(OUTLET_OID).unwrap();
+        let agent = Agent {
+            host: "127.0.0.1",
+            port,
+            community: COMMUNITY,
+            version: SnmpVersion::V1,
+        };
+        assert!(set_integer(&agent, &oid, 1).await.is_ok());
+
+        let agent = Agent {
+            version: SnmpVersion::V2c,
+            ..agent
+        };
+        assert!(set_integer(&agent, &oid, 2).await.is_ok());
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(
+                requests
+                    .iter()
+                    .map(|r| (r.version, r.value))
+                    .collect::<Vec<_>>(),
+                [(0, 1), (1, 2)]
+            );
+            assert_eq!(requests[0].oid, encode_oid(&oid)[2..]);
+        }
+
+        let result = set_integer(&agent, &parse_oid("1.3.6.1.2.1.1.5.0").unwrap(), 1).await;
+        assert!(matches!(result, Err(SnmpError::SetFailed("notWritable"))));
+    }
+}
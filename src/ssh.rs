@@ -0,0 +1,291 @@
+use crate::tcp;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+// Servers may send other lines before the banner, but not too many of them
+const MAX_PRE_BANNER_LINES: usize = 16;
+const MAX_LINE_LENGTH: usize = 255;
+const BANNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Bounds connecting and every blocking libssh2 call, so a session left behind by a cancelled
+// attempt winds down on its own
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum SshError {
+    #[error("Failed to connect: {0}")]
+    ConnectError(#[from] std::io::Error),
+
+    #[error("Server did not send an SSH banner")]
+    NoBanner,
+
+    #[error("Server did not send an SSH banner within {0:?}")]
+    BannerTimedOut(Duration),
+
+    #[error("SSH session failed: {0}")]
+    SessionError(#[from] ssh2::Error),
+
+    #[error("Failed to read identity file {0}: {1}")]
+    IdentityError(String, std::io::Error),
+
+    #[error("SSH authentication as {0} failed: {1}")]
+    AuthenticationFailed(String, ssh2::Error),
+
+    #[error("Failed to read known hosts from {0}: {1}")]
+    KnownHostsError(String, ssh2::Error),
+
+    #[error("Host key of {0} is not in the known hosts file")]
+    UnknownHostKey(String),
+
+    #[error("Host key of {0} does not match the known hosts file")]
+    HostKeyMismatch(String),
+}
+
+type Result<T> = std::result::Result<T, SshError>;
+
+pub struct Login<'a> {
+    pub user: &'a str,
+    pub identity: &'a Path,
+    pub passphrase: Option<&'a str>,
+}
+
+// Where OpenSSH keeps the host keys the user has accepted
+pub fn default_known_hosts() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".ssh/known_hosts"))
+}
+
+pub struct CommandOutput {
+    pub status: i32,
+    pub stdout: String,
+}
+
+// Reads the protocol version line, e.g. "SSH-2.0-OpenSSH_9.6", the first thing every SSH
+// server sends (RFC 4253 4.2)
+pub async fn read_banner(host: &str, port: u16) -> Result<String> {
+    let banner = async {
+        let stream = tokio::net::TcpStream::connect((host, port)).await?;
+        let mut reader =
+            BufReader::new(stream.take((MAX_PRE_BANNER_LINES * MAX_LINE_LENGTH) as u64));
+
+        let mut line = String::new();
+        for _ in 0..MAX_PRE_BANNER_LINES {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                break;
+            }
+            if line.starts_with("SSH-") {
+                return Ok(line.trim_end().to_string());
+            }
+        }
+        Err(SshError::NoBanner)
+    };
+    tokio::time::timeout(BANNER_TIMEOUT, banner)
+        .await
+        .map_err(|_| SshError::BannerTimedOut(BANNER_TIMEOUT))?
+}
+
+// The key is checked before logging in, so a spoofed host never sees the login
+fn check_host_key(
+    session: &ssh2::Session,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    known_hosts: &Path,
+) -> Result<()> {
+    let mut known = session.known_hosts()?;
+    known
+        .read_file(known_hosts, ssh2::KnownHostFileKind::OpenSSH)
+        .map_err(|e| SshError::KnownHostsError(known_hosts.display().to_string(), e))?;
+    match known.check_port(host, port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        ssh2::CheckResult::Mismatch => Err(SshError::HostKeyMismatch(host.into())),
+        ssh2::CheckResult::NotFound | ssh2::CheckResult::Failure => {
+            Err(SshError::UnknownHostKey(host.into()))
+        }
+    }
+}
+
+fn run_command_blocking(
+    host: &str,
+    port: u16,
+    known_hosts: &Path,
+    login: &Login,
+    command: &str,
+) -> Result<CommandOutput> {
+    // libssh2 reports a missing key like any other failed login
+    std::fs::File::open(login.identity)
+        .map_err(|e| SshError::IdentityError(login.identity.display().to_string(), e))?;
+    let stream = tcp::connect_blocking(host, port, SESSION_TIMEOUT)?;
+
+    let mut session = ssh2::Session::new()?;
+    session.set_timeout(SESSION_TIMEOUT.as_millis() as u32);
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| SshError::UnknownHostKey(host.into()))?;
+    check_host_key(&session, host, port, key, known_hosts)?;
+
+    session
+        .userauth_pubkey_file(login.user, None, login.identity, login.passphrase)
+        .map_err(|e| SshError::AuthenticationFailed(login.user.to_string(), e))?;
+
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    // Commands can print anything, which shouldn't fail the check before the regex gets a look
+    let mut stdout = Vec::new();
+    channel.read_to_end(&mut stdout)?;
+    channel.wait_close()?;
+
+    Ok(CommandOutput {
+        status: channel.exit_status()?,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+    })
+}
+
+// libssh2 is blocking, so the session runs on its own thread
+pub async fn run_command(
+    host: &str,
+    port: u16,
+    known_hosts: &Path,
+    login: &Login<'_>,
+    command: &str,
+) -> Result<CommandOutput> {
+    let host = host.to_string();
+    let known_hosts = known_hosts.to_path_buf();
+    let user = login.user.to_string();
+    let identity = login.identity.to_path_buf();
+    let passphrase = login.passphrase.map(|passphrase| passphrase.to_string());
+    let command = command.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let login = Login {
+            user: &user,
+            identity: &identity,
+            passphrase: passphrase.as_deref(),
+        };
+        run_command_blocking(&host, port, &known_hosts, &login, &command)
+    })
+    .await
+    .map_err(|e| SshError::ConnectError(std::io::Error::other(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn spawn_banner_server(greeting: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(greeting).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_read_banner() {
+        let port = spawn_banner_server(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+        assert_eq!(
+            read_banner("127.0.0.1", port).await.unwrap(),
+            "SSH-2.0-OpenSSH_9.6"
+        );
+
+        // Lines before the banner are allowed
+        let port = spawn_banner_server(b"Welcome\r\nSSH-2.0-dropbear\r\n").await;
+        assert_eq!(
+            read_banner("127.0.0.1", port).await.unwrap(),
+            "SSH-2.0-dropbear"
+        );
+
+        let port = spawn_banner_server(b"HTTP/1.1 400 Bad Request\r\n").await;
+        assert!(matches!(
+            read_banner("127.0.0.1", port).await,
+            Err(SshError::NoBanner)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run_command_without_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let identity =
+            std::env::temp_dir().join(format!("rallyup-identity-{}", std::process::id()));
+        std::fs::write(&identity, "").unwrap();
+        let login = Login {
+            user: "root",
+            identity: &identity,
+            passphrase: None,
+        };
+        let known_hosts = Path::new("/nonexistent/known_hosts");
+        assert!(matches!(
+            run_command("127.0.0.1", port, known_hosts, &login, "true").await,
+            Err(SshError::ConnectError(_))
+        ));
+
+        // A missing key is reported before connecting
+        std::fs::remove_file(&identity).unwrap();
+        assert!(matches!(
+            run_command("127.0.0.1", port, known_hosts, &login, "true").await,
+            Err(SshError::IdentityError(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_check_host_key() {
+        // ssh-ed25519 key blobs, with the same byte all through the key
+        let key = |byte: u8| {
+            let mut key = vec![0, 0, 0, 11];
+            key.extend_from_slice(b"ssh-ed25519");
+            key.extend_from_slice(&[0, 0, 0, 32]);
+            key.extend_from_slice(&[byte; 32]);
+            key
+        };
+
+        let path = std::env::temp_dir().join(format!("rallyup-known-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "nas.lan ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJC\n\
+             [nas.lan]:2222 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIENDQ0NDQ0NDQ0NDQ0NDQ0NDQ0NDQ0NDQ0NDQ0NDQ0ND\n",
+        )
+        .unwrap();
+        let session = ssh2::Session::new().unwrap();
+
+        assert!(check_host_key(&session, "nas.lan", 22, &key(0x42), &path).is_ok());
+        assert!(check_host_key(&session, "nas.lan", 2222, &key(0x43), &path).is_ok());
+        assert!(matches!(
+            check_host_key(&session, "nas.lan", 22, &key(0x43), &path),
+            Err(SshError::HostKeyMismatch(_))
+        ));
+        assert!(matches!(
+            check_host_key(&session, "backup.lan", 22, &key(0x42), &path),
+            Err(SshError::UnknownHostKey(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            check_host_key(&session, "nas.lan", 22, &key(0x42), &path),
+            Err(SshError::KnownHostsError(_, _))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_banner_timeout() {
+        // Accepts the connection, but never sends anything
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(matches!(
+            read_banner("127.0.0.1", port).await,
+            Err(SshError::BannerTimedOut(_))
+        ));
+    }
+}
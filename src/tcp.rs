@@ -0,0 +1,250 @@
+use regex::bytes::Regex;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Enough for a greeting or a status reply, a service that sends more is not waited on
+const MAX_REPLY_LENGTH: usize = 64 * 1024;
+
+pub enum Expectation<'a> {
+    Regex(&'a Regex),
+    Bytes(&'a [u8]),
+}
+
+impl Expectation<'_> {
+    fn is_met(&self, reply: &[u8]) -> bool {
+        match self {
+            Expectation::Regex(regex) => regex.is_match(reply),
+            Expectation::Bytes(bytes) => reply.windows(bytes.len()).any(|window| window == *bytes),
+        }
+    }
+}
+
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Hostnames are letters, digits and hyphens, in dot separated labels of up to 63 characters
+pub fn is_valid_host(host: &str) -> bool {
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return true;
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// For blocking clients running on their own thread, which would otherwise hold it until the
+// kernel gives up on an unreachable host
+pub fn connect_blocking(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> io::Result<std::net::TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        match std::net::TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other(format!("failed to resolve {}", host))))
+}
+
+fn describe_reply(reply: &[u8]) -> String {
+    let text = String::from_utf8_lossy(reply);
+    let text = text.trim();
+    if text.is_empty() {
+        return "nothing".into();
+    }
+    match text.char_indices().nth(40) {
+        Some((end, _)) => format!("{:?}...", &text[..end]),
+        None => format!("{:?}", text),
+    }
+}
+
+// Sends the payload, if any, then reads until the reply meets the expectation
+pub async fn converse(
+    host: &str,
+    port: u16,
+    payload: Option<&[u8]>,
+    expectation: Option<Expectation<'_>>,
+    read_timeout: Duration,
+) -> Result<(), String> {
+    let mut stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| format!("failed to connect: {}", e))?;
+
+    if let Some(payload) = payload {
+        stream
+            .write_all(payload)
+            .await
+            .map_err(|e| format!("failed to send: {}", e))?;
+    }
+
+    let Some(expectation) = expectation else {
+        return Ok(());
+    };
+
+    let mut reply = Vec::new();
+    let reading = async {
+        let mut buffer = [0u8; 4096];
+        while reply.len() < MAX_REPLY_LENGTH {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(received) => reply.extend_from_slice(&buffer[..received]),
+            }
+            if expectation.is_met(&reply) {
+                return true;
+            }
+        }
+        false
+    };
+
+    match tokio::time::timeout(read_timeout, reading).await {
+        Ok(true) => Ok(()),
+        _ => Err(format!("unexpected reply: {}", describe_reply(&reply))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Replies PONG to PING, like Redis, after an optional greeting
+    async fn spawn_ping_server(greeting: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    stream.write_all(greeting).await.unwrap();
+                    let mut buffer = [0u8; 64];
+                    while let Ok(received) = stream.read(&mut buffer).await {
+                        if received == 0 {
+                            break;
+                        }
+                        if buffer[..received].starts_with(b"PING") {
+                            // Split in two to make sure replies are accumulated
+                            stream.write_all(b"+PO").await.unwrap();
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            stream.write_all(b"NG\r\n").await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_is_valid_host() {
+        for valid in ["192.168.1.1", "::1", "nas", "nas.lan", "nas-01.home.arpa."] {
+            assert!(is_valid_host(valid), "{} should be valid", valid);
+        }
+        for invalid in [
+            "",
+            "invalid_ip",
+            "-nas.lan",
+            "nas..lan",
+            "nas lan",
+            "nas.lan:80",
+        ] {
+            assert!(!is_valid_host(invalid), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("00ff 10").unwrap(), vec![0x00, 0xFF, 0x10]);
+        assert!(parse_hex("0").is_none());
+        assert!(parse_hex("zz").is_none());
+        assert!(parse_hex("").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_converse() {
+        let port = spawn_ping_server(b"").await;
+        let regex = Regex::new(r"^\+PONG").unwrap();
+        let timeout = Duration::from_secs(1);
+
+        let result = converse(
+            "localhost",
+            port,
+            Some(b"PING\r\n"),
+            Some(Expectation::Regex(&regex)),
+            timeout,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let result = converse(
+            "127.0.0.1",
+            port,
+            Some(b"PING\r\n"),
+            Some(Expectation::Bytes(b"PONG")),
+            timeout,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        // Nothing to say, so the reply never comes
+        let result = converse(
+            "127.0.0.1",
+            port,
+            None,
+            Some(Expectation::Regex(&regex)),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), "unexpected reply: nothing");
+    }
+
+    #[tokio::test]
+    async fn test_converse_greeting() {
+        let port = spawn_ping_server(b"220 mail.lan ESMTP Postfix\r\n").await;
+        let regex = Regex::new(r"^220 ").unwrap();
+        let timeout = Duration::from_secs(1);
+
+        let result = converse(
+            "127.0.0.1",
+            port,
+            None,
+            Some(Expectation::Regex(&regex)),
+            timeout,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let result = converse(
+            "127.0.0.1",
+            port,
+            None,
+            Some(Expectation::Bytes(b"554")),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err(),
+            "unexpected reply: \"220 mail.lan ESMTP Postfix\""
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use crate::tcp;
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::x509::{X509NameRef, X509};
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+
+const SIZE_FINGERPRINT: usize = 32;
+// For connecting, and then for each read or write of the handshake
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to connect: {0}")]
+    ConnectError(#[from] std::io::Error),
+
+    #[error("TLS handshake failed: {0}")]
+    HandshakeError(#[from] native_tls::Error),
+
+    #[error("TLS handshake timed out")]
+    HandshakeTimedOut,
+
+    #[error("Server did not send a certificate")]
+    NoCertificate,
+
+    #[error("Failed to parse the certificate: {0}")]
+    CertificateError(#[from] openssl::error::ErrorStack),
+}
+
+// Where to connect, and the name to send with SNI and verify the certificate against
+pub struct Target<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub server_name: &'a str,
+    pub insecure_skip_verify: bool,
+}
+
+// What the served certificate should look like, every field is optional
+pub struct Expectations<'a> {
+    // Common name, or any DNS or IP subject alternative name
+    pub subject: Option<&'a str>,
+    // Part of the issuer's distinguished name, e.g. "O=Let's Encrypt"
+    pub issuer: Option<&'a str>,
+    pub min_days_valid: Option<u32>,
+    // SHA-256 fingerprint in hex, see `parse_fingerprint`
+    pub fingerprint: Option<&'a str>,
+}
+
+// SHA-256 fingerprint of the certificate, as hex with or without colons
+pub fn parse_fingerprint(fingerprint: &str) -> Option<Vec<u8>> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if hex.len() != SIZE_FINGERPRINT * 2 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn subjects(certificate: &X509) -> Vec<String> {
+    let mut subjects: Vec<String> = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|name| name.to_string()))
+        .collect();
+
+    if let Some(names) = certificate.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dns) = name.dnsname() {
+                subjects.push(dns.to_string());
+            } else if let Some(ip) = name.ipaddress() {
+                let ip = match ip.len() {
+                    4 => <[u8; 4]>::try_from(ip).map(std::net::IpAddr::from).ok(),
+                    16 => <[u8; 16]>::try_from(ip).map(std::net::IpAddr::from).ok(),
+                    _ => None,
+                };
+                subjects.extend(ip.map(|ip| ip.to_string()));
+            }
+        }
+    }
+    subjects
+}
+
+// Wildcards only stand in for the whole leftmost label, so *.lan matches nas.lan, but neither
+// lan nor backup.nas.lan
+fn name_matches(pattern: &str, name: &str) -> bool {
+    if pattern.eq_ignore_ascii_case(name) {
+        return true;
+    }
+    let Some(domain) = pattern.strip_prefix("*.") else {
+        return false;
+    };
+    name.split_once('.')
+        .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(domain))
+}
+
+pub fn check_certificate(certificate: &X509, expected: &Expectations) -> Result<(), String> {
+    let error = |e: openssl::error::ErrorStack| e.to_string();
+
+    if let Some(subject) = expected.subject {
+        let subjects = subjects(certificate);
+        if !subjects.iter().any(|name| name_matches(name, subject)) {
+            return Err(format!(
+                "certificate is for {}, not {}",
+                subjects.join(", "),
+                subject
+            ));
+        }
+    }
+
+    if let Some(issuer) = expected.issuer {
+        let actual = format_name(certificate.issuer_name());
+        if !actual.contains(issuer) {
+            return Err(format!("certificate was issued by {}", actual));
+        }
+    }
+
+    if let Some(min_days_valid) = expected.min_days_valid {
+        let now = Asn1Time::days_from_now(0).map_err(error)?;
+        let remaining = now.diff(certificate.not_after()).map_err(error)?;
+        if remaining.days < min_days_valid as i32 {
+            return Err(format!("certificate expires in {} days", remaining.days));
+        }
+    }
+
+    if let Some(fingerprint) = expected.fingerprint {
+        let fingerprint = parse_fingerprint(fingerprint).ok_or("invalid fingerprint")?;
+        let actual = certificate.digest(MessageDigest::sha256()).map_err(error)?;
+        if actual.as_ref() != fingerprint {
+            return Err("certificate fingerprint does not match".into());
+        }
+    }
+
+    Ok(())
+}
+
+fn fetch_certificate_within(target: &Target, timeout: Duration) -> Result<X509, TlsError> {
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(target.insecure_skip_verify)
+        .danger_accept_invalid_hostnames(target.insecure_skip_verify)
+        .build()?;
+
+    let stream = tcp::connect_blocking(target.host, target.port, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // A read that times out looks like a nonblocking socket that isn't ready yet
+    let stream = connector
+        .connect(target.server_name, stream)
+        .map_err(|e| match e {
+            native_tls::HandshakeError::Failure(e) => TlsError::HandshakeError(e),
+            native_tls::HandshakeError::WouldBlock(_) => TlsError::HandshakeTimedOut,
+        })?;
+
+    let certificate = stream.peer_certificate()?.ok_or(TlsError::NoCertificate)?;
+    Ok(X509::from_der(&certificate.to_der()?)?)
+}
+
+// Completes a TLS handshake and returns the certificate the server sent. This uses native-tls,
+// which the HTTP client is built on too, in blocking mode on a thread of its own.
+pub async fn fetch_certificate(target: &Target<'_>) -> Result<X509, TlsError> {
+    let (host, server_name) = (target.host.to_string(), target.server_name.to_string());
+    let (port, insecure_skip_verify) = (target.port, target.insecure_skip_verify);
+    tokio::task::spawn_blocking(move || {
+        let target = Target {
+            host: &host,
+            port,
+            server_name: &server_name,
+            insecure_skip_verify,
+        };
+        fetch_certificate_within(&target, TIMEOUT)
+    })
+    .await
+    .map_err(|e| TlsError::ConnectError(io::Error::other(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Integer;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+    use std::net::TcpListener;
+
+    fn create_certificate(common_name: &str, days: u32) -> (X509, PKey<openssl::pkey::Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "rallyup").unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = Asn1Integer::from_bn(&BigNum::from_u32(1).unwrap()).unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(common_name)
+            .dns("*.lan")
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    fn spawn_tls_server(certificate: &X509, key: &PKey<openssl::pkey::Private>) -> u16 {
+        let identity = native_tls::Identity::from_pkcs8(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::builder(identity).build().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                // Handshakes that the client gives up on are expected
+                let _ = acceptor.accept(stream.unwrap());
+            }
+        });
+        port
+    }
+
+    fn target(port: u16, insecure_skip_verify: bool) -> Target<'static> {
+        Target {
+            host: "127.0.0.1",
+            port,
+            server_name: "localhost",
+            insecure_skip_verify,
+        }
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let fingerprint = parse_fingerprint(hex).unwrap();
+        assert_eq!(fingerprint.len(), SIZE_FINGERPRINT);
+        assert_eq!(fingerprint[1], 0x11);
+
+        let with_colons = (0..hex.len())
+            .step_by(2)
+            .map(|i| &hex[i..i + 2])
+            .collect::<Vec<&str>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&with_colons).unwrap(), fingerprint);
+
+        assert!(parse_fingerprint("00:11").is_none());
+        assert!(parse_fingerprint(&hex.replace('0', "g")).is_none());
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        let (certificate, _) = create_certificate("nas.example.com", 10);
+        let fingerprint: String = certificate
+            .digest(MessageDigest::sha256())
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let expected = Expectations {
+            subject: Some("NAS.example.com"),
+            issuer: Some("O=rallyup"),
+            min_days_valid: Some(9),
+            fingerprint: Some(&fingerprint),
+        };
+        assert!(check_certificate(&certificate, &expected).is_ok());
+
+        // The IP address and the *.lan wildcard are subject alternative names too
+        for subject in ["127.0.0.1", "pve.lan"] {
+            let expected = Expectations {
+                subject: Some(subject),
+                issuer: None,
+                min_days_valid: None,
+                fingerprint: None,
+            };
+            assert!(check_certificate(&certificate, &expected).is_ok());
+        }
+
+        let zeros = "00".repeat(SIZE_FINGERPRINT);
+        let failures = [
+            (Some("pve.example.com"), None, None, None),
+            (Some("backup.pve.lan"), None, None, None),
+            (None, Some("O=Let's Encrypt"), None, None),
+            (None, None, Some(30), None),
+            (None, None, None, Some(zeros.as_str())),
+            (None, None, None, Some("not a fingerprint")),
+        ];
+        for (subject, issuer, min_days_valid, fingerprint) in failures {
+            let expected = Expectations {
+                subject,
+                issuer,
+                min_days_valid,
+                fingerprint,
+            };
+            assert!(check_certificate(&certificate, &expected).is_err());
+        }
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches("nas.lan", "NAS.lan"));
+        assert!(name_matches("*.lan", "nas.lan"));
+        assert!(name_matches("*.LAN", "nas.lan"));
+        assert!(name_matches("*.lan", "*.lan"));
+        assert!(!name_matches("*.lan", "lan"));
+        assert!(!name_matches("*.lan", ".lan"));
+        assert!(!name_matches("*.lan", "backup.nas.lan"));
+        assert!(!name_matches("nas.*", "nas.lan"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_certificate() {
+        let (certificate, key) = create_certificate("localhost", 10);
+        let port = spawn_tls_server(&certificate, &key);
+
+        // Self-signed, so it's only accepted when not verifying it
+        let result = fetch_certificate(&target(port, false)).await;
+        assert!(matches!(result, Err(TlsError::HandshakeError(_))));
+
+        let served = fetch_certificate(&target(port, true)).await.unwrap();
+        assert_eq!(served.to_der().unwrap(), certificate.to_der().unwrap());
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // Accepts the connection, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let result = fetch_certificate_within(&target(port, true), Duration::from_millis(200));
+        assert!(matches!(result, Err(TlsError::HandshakeTimedOut)));
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const ATTEMPTS: usize = 3;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// Largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+pub async fn connect_addr(address: SocketAddr) -> io::Result<UdpSocket> {
+    let local: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+pub async fn connect(host: &str, port: u16) -> io::Result<UdpSocket> {
+    let address = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("failed to resolve {}", host)))?;
+    connect_addr(address).await
+}
+
+// Sends the request until a reply that `accept` takes comes back, or returns None once every
+// attempt went unanswered. Servers silently drop the requests they don't like, and UDP can lose
+// either message. `request` is called for every attempt, so retries can change it.
+pub async fn exchange<T, E: From<io::Error>>(
+    socket: &UdpSocket,
+    mut request: impl FnMut() -> Result<Vec<u8>, E>,
+    mut accept: impl FnMut(&[u8]) -> Option<Result<T, E>>,
+) -> Result<Option<T>, E> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request()?).await?;
+        let deadline = tokio::time::Instant::now() + REPLY_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            if let Some(result) = accept(&buffer[..received?]) {
+                return result.map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_exchange() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = connect_addr(server.local_addr().unwrap()).await.unwrap();
+
+        // Drops the first request, then answers with a stray reply before the real one
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 16];
+            server.recv_from(&mut buffer).await.unwrap();
+            let (received, client) = server.recv_from(&mut buffer).await.unwrap();
+            server.send_to(b"stray", client).await.unwrap();
+            server.send_to(&buffer[..received], client).await.unwrap();
+        });
+
+        let mut attempt = 0u8;
+        let reply = exchange(
+            &socket,
+            || {
+                attempt += 1;
+                Ok::<_, io::Error>(vec![attempt])
+            },
+            |reply| (reply != b"stray").then(|| Ok(reply.to_vec())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply, Some(vec![2]));
+
+        // Nobody answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = connect_addr(silent.local_addr().unwrap()).await.unwrap();
+        let reply = exchange(&socket, || Ok::<_, io::Error>(vec![0]), |_| Some(Ok(()))).await;
+        assert!(matches!(reply, Ok(None)));
+    }
+}
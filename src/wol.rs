@@ -2,6 +2,7 @@ use pnet::datalink::{self, Channel::Ethernet};
 use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
 use pnet::packet::{MutablePacket, Packet};
 use pnet::util::MacAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
 
 use thiserror::Error;
 
@@ -19,6 +20,9 @@ pub enum WOLError {
     #[error("Invalid MAC address: {0}")]
     InvalidMAC(String),
 
+    #[error("Invalid SecureOn password: {0}")]
+    InvalidPassword(String),
+
     #[error("Failed to find network interface: {0}")]
     InterfaceNotFound(String),
 
@@ -36,7 +40,7 @@ type Result<T> = std::result::Result<T, WOLError>;
 // | Destination MAC  | Source MAC      | VLAN EtherType | VLAN Tag | WOL EtherType | WOL Magic Packet                              |
 // | (Broadcast MAC)  | (Interface MAC) | (0x8100)       | (VLAN ID)| (0x0842)      | (FF:FF:FF + 16 * Target MAC)                  |
 // -----------------------------------------------------------------------------
-// | 6 bytes          | 6 bytes         | 2 bytes        | 2 bytes  | 2 bytes       | 102 bytes                                     |
+// | 6 bytes          | 6 bytes         | 2 bytes        | 2 bytes  | 2 bytes       | 102 bytes (+ 4 or 6 bytes SecureOn password)  |
 // -----------------------------------------------------------------------------
 // Detailed Breakdown of Each Component:
 // - Destination MAC (6 bytes): The destination MAC address, usually the broadcast MAC (FF:FF:FF:FF:FF:FF) for WOL packets.
@@ -45,28 +49,79 @@ type Result<T> = std::result::Result<T, WOLError>;
 // - VLAN Tag (2 bytes): The VLAN tag, which contains 12 bits for the VLAN ID and 4 bits for priority and CFI (Canonical Format Indicator).
 // - WOL EtherType (2 bytes): The EtherType field indicating a Wake-on-LAN packet, which is 0x0842.
 // - WOL Magic Packet (102 bytes): The WOL magic packet, consisting of 6 bytes of FF followed by the target MAC address repeated 16 times.
+//   NICs configured for SecureOn also expect a 4 or 6 byte password right after the repeated MAC addresses.
 
-fn create_wol_payload(mac: MacAddr) -> Vec<u8> {
+fn create_wol_payload(mac: MacAddr, password: &[u8]) -> Vec<u8> {
     // 6 bytes of FF followed by target MAC address repeated 16 times
-    let mut packet = vec![0xFF; 6];
+    let mut packet = Vec::with_capacity(SIZE_WOL_PAYLOAD + password.len());
+    packet.extend_from_slice(&[0xFF; 6]);
     for _ in 0..16 {
         packet.extend_from_slice(&mac.octets());
     }
+    packet.extend_from_slice(password);
     packet
 }
 
+// SecureOn passwords are usually written like a MAC address (`01:23:45:67:89:AB`),
+// but we also accept plain hex (`01234567`) and dash separated bytes
+pub fn parse_secureon_password(password: &str) -> Result<Vec<u8>> {
+    let invalid = || WOLError::InvalidPassword(password.to_string());
+
+    let groups: Vec<&str> = if password.contains([':', '-']) {
+        password.split([':', '-']).collect()
+    } else {
+        (0..password.len())
+            .step_by(2)
+            .map(|i| password.get(i..i + 2).unwrap_or(""))
+            .collect()
+    };
+
+    let bytes = groups
+        .into_iter()
+        .map(|group| {
+            if group.len() != 2 {
+                return Err(invalid());
+            }
+            u8::from_str_radix(group, 16).map_err(|_| invalid())
+        })
+        .collect::<Result<Vec<u8>>>()?;
+
+    if bytes.len() != 4 && bytes.len() != 6 {
+        return Err(invalid());
+    }
+
+    Ok(bytes)
+}
+
+fn parse_optional_password(password: Option<&str>) -> Result<Vec<u8>> {
+    password
+        .map(parse_secureon_password)
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
 fn vlan_to_bytes(vlan: u16) -> Vec<u8> {
     // Do not need priority bits, using only the remaining 14 bits of the tag
     let vlan_tag = (vlan & 0x0FFF).to_be_bytes();
     vlan_tag.to_vec()
 }
 
-pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u16>) -> Result<()> {
-    let mac = maybe_mac
+fn parse_mac(maybe_mac: &str) -> Result<MacAddr> {
+    maybe_mac
         .parse::<MacAddr>()
-        .map_err(|_| WOLError::InvalidMAC(maybe_mac.to_string()))?;
+        .map_err(|_| WOLError::InvalidMAC(maybe_mac.to_string()))
+}
 
-    let wol_packet = create_wol_payload(mac);
+pub fn send_wol_packet(
+    maybe_mac: &str,
+    interface_name: &str,
+    vlan_id: Option<u16>,
+    password: Option<&str>,
+) -> Result<()> {
+    let mac = parse_mac(maybe_mac)?;
+    let password = parse_optional_password(password)?;
+
+    let wol_packet = create_wol_payload(mac, &password);
 
     let interface = datalink::interfaces()
         .into_iter()
@@ -76,8 +131,7 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
     let mut tx = match datalink::channel(&interface, Default::default()) {
         Ok(Ethernet(tx, _)) => tx,
         Ok(_) => {
-            return Err(WOLError::NetworkError(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(WOLError::NetworkError(std::io::Error::other(
                 "unhandled channel type for this interface",
             )))
         }
@@ -88,7 +142,7 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
         SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE
     } else {
         0
-    } + SIZE_WOL_PAYLOAD;
+    } + wol_packet.len();
 
     let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
     let mut buffer = vec![0u8; packet_size];
@@ -101,8 +155,7 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
     if let Some(mac) = interface.mac {
         packet.set_source(mac);
     } else {
-        return Err(WOLError::NetworkError(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(WOLError::NetworkError(std::io::Error::other(
             "failed to get source MAC address of the interface",
         )));
     }
@@ -125,9 +178,8 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
 
     packet.payload_mut()[payload_offset..].copy_from_slice(&wol_packet);
 
-    tx.send_to(packet.packet(), None).ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::Other, "failed to send WOL packet")
-    })??;
+    tx.send_to(packet.packet(), None)
+        .ok_or_else(|| std::io::Error::other("failed to send WOL packet"))??;
 
     //println!(
     //    "WOL packet sent successfully over interface: {}",
@@ -136,3 +188,112 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
 
     Ok(())
 }
+
+// The UDP form of the magic packet is just the WOL payload sent as a datagram, usually to port 9
+// (discard) or 7 (echo). Unlike the raw Ethernet frame, it does not need CAP_NET_RAW and can be
+// routed, so sending it to a subnet-directed broadcast address reaches machines behind an L3 hop.
+pub fn send_wol_udp_packet(
+    maybe_mac: &str,
+    address: IpAddr,
+    port: u16,
+    password: Option<&str>,
+) -> Result<()> {
+    let mac = parse_mac(maybe_mac)?;
+    let password = parse_optional_password(password)?;
+
+    let wol_packet = create_wol_payload(mac, &password);
+
+    let socket = match address {
+        IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    // Needed for both the limited (255.255.255.255) and subnet-directed broadcast addresses
+    if address.is_ipv4() {
+        socket.set_broadcast(true)?;
+    }
+
+    let sent = socket.send_to(&wol_packet, (address, port))?;
+    if sent != wol_packet.len() {
+        return Err(WOLError::WOLPacketError(format!(
+            "only sent {} of {} bytes",
+            sent,
+            wol_packet.len()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_wol_payload() {
+        let mac = "00:11:22:33:44:55".parse::<MacAddr>().unwrap();
+        let payload = create_wol_payload(mac, &[]);
+
+        assert_eq!(payload.len(), SIZE_WOL_PAYLOAD);
+        assert_eq!(payload[..6], [0xFF; 6]);
+        for repeat in payload[6..].chunks(6) {
+            assert_eq!(repeat, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        }
+
+        // SecureOn password goes at the very end
+        let payload = create_wol_payload(mac, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(payload.len(), SIZE_WOL_PAYLOAD + 4);
+        assert_eq!(payload[SIZE_WOL_PAYLOAD..], [0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn test_parse_secureon_password() {
+        assert_eq!(
+            parse_secureon_password("01:23:45:67:89:ab").unwrap(),
+            vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xAB]
+        );
+        assert_eq!(
+            parse_secureon_password("01-23-45-67").unwrap(),
+            vec![0x01, 0x23, 0x45, 0x67]
+        );
+        assert_eq!(
+            parse_secureon_password("0123456789AB").unwrap(),
+            vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xAB]
+        );
+
+        // Wrong length
+        assert!(parse_secureon_password("01:23:45").is_err());
+        assert!(parse_secureon_password("0123456789").is_err());
+        assert!(parse_secureon_password("012345678").is_err());
+        // Not hex
+        assert!(parse_secureon_password("01:23:45:zz").is_err());
+        assert!(parse_secureon_password("1:23:45:67:8").is_err());
+        assert!(parse_secureon_password("").is_err());
+    }
+
+    #[test]
+    fn test_send_wol_udp_packet() {
+        let listener = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let address = "127.0.0.1".parse().unwrap();
+        let mac = "00:11:22:33:44:55".parse::<MacAddr>().unwrap();
+        let mut buffer = [0u8; 256];
+
+        send_wol_udp_packet("00:11:22:33:44:55", address, port, None).unwrap();
+        let (received, _) = listener.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[..received], create_wol_payload(mac, &[]));
+
+        send_wol_udp_packet("00:11:22:33:44:55", address, port, Some("de:ad:be:ef")).unwrap();
+        let (received, _) = listener.recv_from(&mut buffer).unwrap();
+        assert_eq!(
+            buffer[..received],
+            create_wol_payload(mac, &[0xDE, 0xAD, 0xBE, 0xEF])
+        );
+    }
+
+    #[test]
+    fn test_send_wol_udp_packet_invalid_mac() {
+        let result = send_wol_udp_packet("not a mac", "127.0.0.1".parse().unwrap(), 9, None);
+        assert!(matches!(result, Err(WOLError::InvalidMAC(_))));
+    }
+}